@@ -0,0 +1,66 @@
+//! Embed the git commit and the build date into the version printed by `--version`.
+
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let mut metadata = Vec::new();
+    if let Some(commit) = git_commit() {
+        metadata.push(commit);
+    }
+    metadata.push(build_date());
+
+    println!(
+        "cargo:rustc-env=WLR_WHICH_KEY_VERSION={} ({})",
+        env::var("CARGO_PKG_VERSION").unwrap(),
+        metadata.join(" "),
+    );
+}
+
+/// The short hash of the current commit, with a `-dirty` suffix if there are uncommitted changes.
+/// `None` when not building from a git checkout.
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args([
+            "describe",
+            "--always",
+            "--dirty",
+            "--abbrev=7",
+            "--exclude=*",
+        ])
+        .output()
+        .ok()?;
+    let commit = String::from_utf8(output.stdout).ok()?;
+    let commit = commit.trim();
+    (output.status.success() && !commit.is_empty()).then(|| commit.to_owned())
+}
+
+/// The build date as `YYYY-MM-DD`, from `SOURCE_DATE_EPOCH` for reproducible builds.
+fn build_date() -> String {
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+
+    // Convert days since the epoch to a civil date (see http://howardhinnant.github.io/date_algorithms.html)
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
@@ -0,0 +1,5 @@
+//! The KDE blur protocol, which is not included in `wayrs_protocols`.
+
+use wayrs_client::protocol::*;
+
+wayrs_client::generate!("protocols/kde-blur.xml");
@@ -1,5 +1,5 @@
-use pangocairo::cairo::Context;
-use serde::de;
+use pangocairo::cairo::{Context, Gradient, LinearGradient};
+use serde::{de, Deserialize};
 use std::fmt;
 use std::str::FromStr;
 
@@ -27,6 +27,14 @@ impl Color {
         }
     }
 
+    pub fn add_color_stop(self, gradient: &Gradient, offset: f64) {
+        if self.alpha.is_nan() {
+            gradient.add_color_stop_rgb(offset, self.red, self.green, self.blue);
+        } else {
+            gradient.add_color_stop_rgba(offset, self.red, self.green, self.blue, self.alpha);
+        }
+    }
+
     pub fn from_rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
         Self {
             red: r as f64 / 255.0,
@@ -45,25 +53,158 @@ impl Color {
     }
 }
 
+/// A fill which is either a solid color or a linear gradient.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Background {
+    Solid(Color),
+    Gradient {
+        /// Evenly spaced color stops.
+        gradient: Vec<Color>,
+        /// Direction of the gradient in degrees, clockwise. `0` is left-to-right.
+        #[serde(default)]
+        angle: f64,
+    },
+}
+
+impl Background {
+    /// Set this background as the source of `cr`, covering a `width`x`height` area at the origin.
+    pub fn apply(&self, cr: &Context, width: f64, height: f64) {
+        match self {
+            Self::Solid(color) => color.apply(cr),
+            Self::Gradient { gradient, angle } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                // Half of the gradient line, long enough for the stops to reach the corners
+                let half_len = (width * cos.abs() + height * sin.abs()) * 0.5;
+                let (cx, cy) = (width * 0.5, height * 0.5);
+                let pattern = LinearGradient::new(
+                    cx - cos * half_len,
+                    cy - sin * half_len,
+                    cx + cos * half_len,
+                    cy + sin * half_len,
+                );
+                let last = gradient.len().saturating_sub(1).max(1) as f64;
+                for (i, color) in gradient.iter().enumerate() {
+                    color.add_color_stop(&pattern, i as f64 / last);
+                }
+                cr.set_source(&pattern).unwrap();
+            }
+        }
+    }
+}
+
+/// Named colors, as `0xRRGGBBAA`.
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("transparent", 0x00000000),
+    ("black", 0x000000ff),
+    ("white", 0xffffffff),
+    ("gray", 0x808080ff),
+    ("grey", 0x808080ff),
+    ("silver", 0xc0c0c0ff),
+    ("red", 0xff0000ff),
+    ("maroon", 0x800000ff),
+    ("orange", 0xffa500ff),
+    ("yellow", 0xffff00ff),
+    ("olive", 0x808000ff),
+    ("lime", 0x00ff00ff),
+    ("green", 0x008000ff),
+    ("aqua", 0x00ffffff),
+    ("cyan", 0x00ffffff),
+    ("teal", 0x008080ff),
+    ("blue", 0x0000ffff),
+    ("navy", 0x000080ff),
+    ("fuchsia", 0xff00ffff),
+    ("magenta", 0xff00ffff),
+    ("purple", 0x800080ff),
+    ("pink", 0xffc0cbff),
+    ("brown", 0xa52a2aff),
+];
+
 impl FromStr for Color {
     type Err = ();
 
     fn from_str(color: &str) -> Result<Self, Self::Err> {
-        let rgb = color.get(1..7).ok_or(())?;
-        let rgb = u32::from_str_radix(rgb, 16).map_err(|_| ())?;
-        let r = (rgb >> 16) as u8;
-        let g = (rgb >> 8) as u8;
-        let b = rgb as u8;
+        let color = color.trim();
+
+        if let Some(args) = color
+            .strip_prefix("rgba(")
+            .or_else(|| color.strip_prefix("rgb("))
+        {
+            return parse_rgb_function(args.strip_suffix(')').ok_or(())?);
+        }
 
-        let a = match color.get(7..9) {
-            Some(a) => u8::from_str_radix(a, 16).map_err(|_| ())?,
-            None => 255,
-        };
+        if let Some((_, hex)) = NAMED_COLORS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(color))
+        {
+            return Ok(Self::from_rgba_hex(*hex));
+        }
 
-        Ok(Self::from_rgba(r, g, b, a))
+        let hex = color.strip_prefix('#').ok_or(())?;
+        // `from_str_radix` would accept a sign
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(());
+        }
+        let digits = |range: std::ops::Range<usize>| u8::from_str_radix(&hex[range], 16).unwrap();
+
+        match hex.len() {
+            // Short forms, where each digit is repeated: `#RGB` and `#RGBA`
+            3 | 4 => {
+                let digit = |i| digits(i..i + 1) * 0x11;
+                let a = if hex.len() == 4 { digit(3) } else { 255 };
+                Ok(Self::from_rgba(digit(0), digit(1), digit(2), a))
+            }
+            6 | 8 => {
+                let a = if hex.len() == 8 { digits(6..8) } else { 255 };
+                Ok(Self::from_rgba(digits(0..2), digits(2..4), digits(4..6), a))
+            }
+            _ => Err(()),
+        }
     }
 }
 
+/// Parse the arguments of `rgb(r, g, b)` or `rgba(r, g, b, a)`, where the components are in
+/// `0..=255` and the alpha is in `0.0..=1.0`. Any of them may be given as a percentage instead.
+fn parse_rgb_function(args: &str) -> Result<Color, ()> {
+    let mut args = args.split(',').map(str::trim);
+    let mut next_component = || match args.next().ok_or(())? {
+        arg if arg.ends_with('%') => Ok((parse_percentage(arg)? * 255.0).round() as u8),
+        arg => arg.parse::<u8>().map_err(|_| ()),
+    };
+    let r = next_component()?;
+    let g = next_component()?;
+    let b = next_component()?;
+
+    let a = match args.next() {
+        Some(a) => {
+            let a = match a.ends_with('%') {
+                true => parse_percentage(a)?,
+                false => a.parse().map_err(|_| ())?,
+            };
+            if !(0.0..=1.0).contains(&a) {
+                return Err(());
+            }
+            (a * 255.0).round() as u8
+        }
+        None => 255,
+    };
+
+    if args.next().is_some() {
+        return Err(());
+    }
+
+    Ok(Color::from_rgba(r, g, b, a))
+}
+
+/// Parse a percentage like `50%` into `0.0..=1.0`.
+fn parse_percentage(arg: &str) -> Result<f64, ()> {
+    let percentage: f64 = arg.strip_suffix('%').ok_or(())?.parse().map_err(|_| ())?;
+    if !(0.0..=100.0).contains(&percentage) {
+        return Err(());
+    }
+    Ok(percentage / 100.0)
+}
+
 impl<'de> de::Deserialize<'de> for Color {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -75,7 +216,7 @@ impl<'de> de::Deserialize<'de> for Color {
             type Value = Color;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("RBG or RGBA color (in hex)")
+                formatter.write_str("color (hex, rgb(), rgba() or a color name)")
             }
 
             fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
@@ -83,10 +224,144 @@ impl<'de> de::Deserialize<'de> for Color {
                 E: de::Error,
             {
                 s.parse()
-                    .map_err(|_| E::custom(format!("'{s}' is not a valid RGB/RGBA color")))
+                    .map_err(|_| E::custom(format!("'{s}' is not a valid color")))
             }
         }
 
         deserializer.deserialize_str(ColorVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The color as bytes, with `None` for the alpha of opaque colors.
+    fn bytes(color: Color) -> (u8, u8, u8, Option<u8>) {
+        let byte = |c: f64| (c * 255.0).round() as u8;
+        let alpha = (!color.alpha.is_nan()).then(|| byte(color.alpha));
+        (byte(color.red), byte(color.green), byte(color.blue), alpha)
+    }
+
+    fn parse(color: &str) -> (u8, u8, u8, Option<u8>) {
+        bytes(
+            color
+                .parse()
+                .unwrap_or_else(|_| panic!("{color:?} should be valid")),
+        )
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse("#282828"), (0x28, 0x28, 0x28, None));
+        assert_eq!(parse("#8ec07c80"), (0x8e, 0xc0, 0x7c, Some(0x80)));
+        assert_eq!(parse("#FBF1C7"), (0xfb, 0xf1, 0xc7, None));
+    }
+
+    #[test]
+    fn short_hex() {
+        assert_eq!(parse("#abc"), parse("#aabbcc"));
+        assert_eq!(parse("#abc"), (0xaa, 0xbb, 0xcc, None));
+        assert_eq!(parse("#abcd"), (0xaa, 0xbb, 0xcc, Some(0xdd)));
+        assert_eq!(parse("#abcd"), parse("#aabbccdd"));
+        assert_eq!(parse("#FFF"), (0xff, 0xff, 0xff, None));
+        assert_eq!(parse("#000f"), (0, 0, 0, None));
+    }
+
+    #[test]
+    fn invalid_short_hex() {
+        for color in ["#ab", "#abcde", "#abg", "abc", "#+ab"] {
+            assert!(
+                color.parse::<Color>().is_err(),
+                "{color:?} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn opaque_alpha_is_nan() {
+        // Fully opaque colors use `set_source_rgb`, whether the alpha is given or not
+        let alpha = |color: &str| color.parse::<Color>().unwrap().alpha;
+        assert!(alpha("#ffffff").is_nan());
+        assert!(alpha("#ffffffff").is_nan());
+        assert!(alpha("rgba(1, 2, 3, 1.0)").is_nan());
+        assert!(alpha("red").is_nan());
+        assert_eq!(alpha("#ffffff00"), 0.0);
+    }
+
+    #[test]
+    fn named() {
+        assert_eq!(parse("red"), (0xff, 0x00, 0x00, None));
+        assert_eq!(parse("Navy"), (0x00, 0x00, 0x80, None));
+        assert_eq!(parse(" white "), (0xff, 0xff, 0xff, None));
+        assert_eq!(parse("grey"), parse("gray"));
+        assert_eq!(parse("transparent"), (0, 0, 0, Some(0)));
+        assert!("reddish".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn rgb_function() {
+        assert_eq!(parse("rgb(40,40,40)"), (40, 40, 40, None));
+        assert_eq!(parse("rgb( 1 , 2 , 3 )"), (1, 2, 3, None));
+        assert_eq!(parse("rgba(40, 40, 40, 0.5)"), (40, 40, 40, Some(128)));
+        assert_eq!(parse("rgba(0, 0, 0, 0)"), (0, 0, 0, Some(0)));
+        // The alpha is optional in `rgba()` and allowed in `rgb()`
+        assert_eq!(parse("rgba(1, 2, 3)"), (1, 2, 3, None));
+        assert_eq!(parse("rgb(1, 2, 3, 0.2)"), (1, 2, 3, Some(51)));
+    }
+
+    #[test]
+    fn rgb_function_percentages() {
+        assert_eq!(parse("rgb(100%, 0%, 50%)"), (255, 0, 128, None));
+        assert_eq!(parse("rgba(40, 40, 40, 50%)"), (40, 40, 40, Some(128)));
+        assert_eq!(parse("rgba(0%, 100%, 0%, 100%)"), (0, 255, 0, None));
+    }
+
+    #[test]
+    fn invalid_rgb_function() {
+        let invalid = [
+            // Out of range
+            "rgb(256, 0, 0)",
+            "rgb(-1, 0, 0)",
+            "rgba(0, 0, 0, 1.5)",
+            "rgba(0, 0, 0, -0.1)",
+            "rgb(101%, 0%, 0%)",
+            "rgba(0, 0, 0, -5%)",
+            // Wrong number of parts
+            "rgb(1, 2)",
+            "rgba(1, 2, 3, 0.5, 6)",
+            "rgb()",
+            // Not numbers or not closed
+            "rgb(a, b, c)",
+            "rgb(%, 0, 0)",
+            "rgb(1.5, 0, 0)",
+            "rgba(0, 0, 0, half)",
+            "rgb(1, 2, 3",
+        ];
+        for color in invalid {
+            assert!(
+                color.parse::<Color>().is_err(),
+                "{color:?} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_hex() {
+        let invalid = [
+            "",
+            "#",
+            "282828",
+            "#12345g",
+            "#+12345",
+            "#1234567",
+            "#12345678a",
+        ];
+        for color in invalid {
+            assert!(
+                color.parse::<Color>().is_err(),
+                "{color:?} should be invalid"
+            );
+        }
+    }
+}
@@ -1,18 +1,24 @@
+use std::collections::HashMap;
 use std::env;
 use std::fmt;
 use std::fs::read_to_string;
+use std::io;
+use std::iter;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use anyhow::{bail, Context, Result};
 use indexmap::IndexMap;
-use pangocairo::pango::FontDescription;
+use pangocairo::pango::{self, FontDescription};
 use serde::{de, Deserialize};
 use smart_default::SmartDefault;
+use wayrs_protocols::wlr_layer_shell_unstable_v1::zwlr_layer_shell_v1::Layer;
 use wayrs_protocols::wlr_layer_shell_unstable_v1::zwlr_layer_surface_v1::Anchor;
+use wayrs_utils::keyboard::xkb;
 
-use crate::color::Color;
-use crate::key::Key;
+use crate::color::{Background, Color};
+use crate::key::{Key, LayoutMode, Modifier, ModifierStyle, SingleKey};
 
 #[derive(Deserialize, Default)]
 #[serde(transparent)]
@@ -21,65 +27,673 @@ pub struct Entries(pub IndexMap<Key, Entry>);
 #[derive(Deserialize, SmartDefault)]
 #[serde(deny_unknown_fields, default)]
 pub struct Config {
-    #[default(Color::from_rgba_hex(0x282828ff))]
-    pub background: Color,
+    #[default(Background::Solid(Color::from_rgba_hex(0x282828ff)))]
+    pub background: Background,
     #[default(Color::from_rgba_hex(0xfbf1c7ff))]
     pub color: Color,
     #[default(Color::from_rgba_hex(0x8ec07cff))]
     pub border: Color,
+    /// Text color of disabled entries.
+    #[default(Color::from_rgba_hex(0x928374ff))]
+    pub disabled_color: Color,
+    pub background_image: Option<PathBuf>,
+    pub background_image_fit: BackgroundImageFit,
+    /// PNG image shown above the entries of the top-level page, scaled to `header_height`.
+    pub header_image: Option<PathBuf>,
+    #[default(32.0)]
+    pub header_height: f64,
+    /// Use `<name>@2x.png` next to `header_image`, if it exists, at scales of 2 and more.
+    #[default(true)]
+    pub hidpi_images: bool,
+    /// Delay in milliseconds before the menu is shown. Keys are handled during the delay.
+    pub show_delay: u64,
+    /// Duration of the fade-in animation in milliseconds, `0` to show the menu at once.
+    pub fade_in_ms: u64,
+    /// Close the menu if no key is pressed for this many milliseconds.
+    pub idle_timeout_ms: Option<u64>,
+    /// Close the menu when it loses keyboard focus.
+    pub close_on_focus_loss: bool,
+    /// Delay in milliseconds before a held key starts repeating, overrides the compositor's
+    /// setting.
+    pub repeat_delay: Option<u64>,
+    /// Interval in milliseconds between repeats of a held key, overrides the compositor's
+    /// setting.
+    pub repeat_interval: Option<u64>,
+    /// Animation used when switching between pages.
+    pub transition: Transition,
+    /// Duration of the page transition in milliseconds.
+    #[default(150)]
+    pub transition_ms: u64,
+    /// Blur the area behind the window, on compositors which support the KDE blur protocol.
+    pub blur: bool,
+    /// Multiplier for the alpha of everything drawn, clamped to `0.0..=1.0`.
+    #[default(1.0)]
+    pub opacity: f64,
 
+    /// The layer the menu is shown on.
+    pub layer: ConfigLayer,
+    /// Space reserved by the compositor along the anchored edge, so other surfaces do not
+    /// overlap the menu.
+    pub exclusive_zone: Option<ExclusiveZone>,
     pub anchor: ConfigAnchor,
     pub margin_top: i32,
     pub margin_right: i32,
     pub margin_bottom: i32,
     pub margin_left: i32,
+    /// Reduce the margins so the whole window stays on the output.
+    #[default(true)]
+    pub clamp_to_output: bool,
+    /// Exact position of the window, overrides `anchor` and the margins.
+    pub position: Option<Position>,
+
+    /// The name of the output to show the menu on.
+    pub output: Option<String>,
+    /// Placement overrides, keyed by the output name.
+    pub per_output: HashMap<String, OutputConfig>,
 
     #[default(Font::new("monospace 10"))]
     pub font: Font,
+    /// Fonts of the key and description columns, `font` if not set.
+    pub key_font: Option<Font>,
+    pub desc_font: Option<Font>,
+    /// Underline the key's character in the description.
+    pub emphasize_key_in_desc: bool,
+    /// Make all fonts bold or italic.
+    pub bold: bool,
+    pub italic: bool,
+    /// Extra space between letters, in points.
+    pub letter_spacing: f64,
+    /// Bounds for the width of the window, including padding and border.
+    pub min_width: Option<f64>,
+    pub max_width: Option<f64>,
+    /// Descriptions wider than this are ellipsized, or wrapped if `wrap_desc` is set.
+    pub max_desc_width: Option<f64>,
+    pub wrap_desc: bool,
+    /// Alignment of the menu when the window is wider than it.
+    pub content_align: Align,
+    /// Size the window to fit every page, so it does not resize when switching pages. `true`
+    /// is the same as `largest`.
+    #[serde(deserialize_with = "deserialize_fixed_size")]
+    pub fixed_size: Option<FixedSize>,
+    /// Split pages into columns with at most this many rows.
+    pub rows_per_column: Option<usize>,
+    /// Use as many columns as fit on the output, instead of `rows_per_column`.
+    pub auto_columns: bool,
+    /// Whether entries may be split into multiple columns.
+    pub layout: Layout,
+    /// The order of entries on each page, can be overridden per submenu.
+    pub sort: Sort,
+    /// Show the position of each entry on its page in front of its key.
+    pub show_numbers: bool,
+    /// Other configs whose menus Tab switches to, in order.
+    pub cycle_menus: Vec<String>,
+    /// Space between columns.
+    #[default(25.0)]
+    pub column_padding: f64,
+    /// A vertical line drawn in the middle of the space between columns.
+    pub column_separator: Option<ColumnSeparator>,
+    /// Give all rows of a page the height of the tallest one.
+    #[default(true)]
+    pub uniform_rows: bool,
+    /// Extra space between rows.
+    pub row_spacing: f64,
+    /// Multiplier for the height of rows.
+    #[default(1.0)]
+    pub line_height: f64,
+    /// Render keys with a monospace font of the same size, so that they line up even when `font`
+    /// is proportional.
+    pub monospace_keys: bool,
+    /// Show special keys as compact symbols, e.g. `↵` for `Return`.
+    pub pretty_keys: bool,
+    /// How modifiers are shown, by default as written in the config.
+    pub modifier_style: Option<ModifierStyle>,
+    #[default(Align::Right)]
+    pub key_align: Align,
+    pub desc_align: Align,
     #[default(" ➜ ".into())]
     pub separator: String,
     #[default(4.0)]
     pub border_width: f64,
-    #[default(20.0)]
-    pub corner_r: f64,
-    // defaults to `corner_r`
+    #[default(CornerRadius::uniform(20.0))]
+    pub corner_r: CornerRadius,
+    // defaults to the largest of `corner_r`
     pub padding: Option<f64>,
 
+    #[default(Color::from_rgba_hex(0x00000080))]
+    pub shadow_color: Color,
+    pub shadow_blur: f64,
+    pub shadow_offset: [f64; 2],
+
+    /// Match letter keys regardless of their case.
+    pub case_insensitive_keys: bool,
+    /// Resolve keys as if CapsLock was off. NumLock is kept, since it changes the meaning of
+    /// keypad keys rather than their case.
+    #[default(true)]
+    pub ignore_lock_mods: bool,
+    /// Match keypad keys which type a character as the corresponding main keys, e.g. `KP_1` as
+    /// `1` and `KP_Enter` as `Return`.
+    pub numpad_as_digits: bool,
+    /// Which keyboard layouts are used to match keys.
+    pub kbd_layout_mode: LayoutMode,
+    /// Keys which close the menu, see `Config::cancel_keys`.
+    pub cancel_keys: Option<Vec<SingleKey>>,
+    /// Whether to use the built-in cancel keys when `cancel_keys` is not set.
+    #[default(true)]
+    pub implicit_cancel: bool,
+    /// Keys which go back to the parent page.
+    #[default(vec![SingleKey::new(xkb::Keysym::BackSpace, false)])]
+    pub parent_keys: Vec<SingleKey>,
+    /// Key which copies the current page as text to the clipboard.
+    pub copy_key: Option<SingleKey>,
+    /// Entries of the current page selected by scrolling the mouse wheel over the menu.
+    pub scroll_up: Option<Key>,
+    pub scroll_down: Option<Key>,
+    /// Warn about entries which override the cancel or parent keys.
+    #[default(true)]
+    pub warn_shadowed_keys: bool,
+    /// Holding this modifier while selecting an entry keeps the menu open.
+    pub sticky_modifier: Option<Modifier>,
+    /// Releasing this modifier closes the menu.
+    pub close_on_release: Option<Modifier>,
+    /// Entries of the current page selected by pressing and releasing a modifier on its own.
+    pub modifier_actions: HashMap<Modifier, Key>,
+
     pub menu: Entries,
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnSeparator {
+    pub color: Color,
+    #[serde(default = "default_column_separator_width")]
+    pub width: f64,
+}
+
+fn default_column_separator_width() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct OutputConfig {
+    pub anchor: Option<ConfigAnchor>,
+    pub margin_top: Option<i32>,
+    pub margin_right: Option<i32>,
+    pub margin_bottom: Option<i32>,
+    pub margin_left: Option<i32>,
+    pub position: Option<Position>,
+}
+
+/// The position of the window's top-left corner in surface-local pixels.
+///
+/// Negative values are measured from the right or bottom edge of the output to the window's
+/// right or bottom edge.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Position {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// The resolved anchor and margins of the window.
+pub struct Placement {
+    pub anchor: ConfigAnchor,
+    pub margin_top: i32,
+    pub margin_right: i32,
+    pub margin_bottom: i32,
+    pub margin_left: i32,
+}
+
 #[derive(Deserialize)]
 #[serde(untagged, deny_unknown_fields)]
 pub enum Entry {
     Cmd {
-        cmd: String,
+        /// One or more commands, run in order until one fails.
+        #[serde(deserialize_with = "deserialize_cmd")]
+        cmd: Vec<String>,
+        #[serde(deserialize_with = "deserialize_desc")]
         desc: String,
+        /// Replace `$(command)` in `desc` with the output of the command.
+        #[serde(default)]
+        desc_cmd: bool,
+        #[serde(default)]
+        keep_open: KeepOpen,
+        /// What to do after running the command, overrides `keep_open`.
+        #[serde(default)]
+        then: Option<Then>,
+        /// Expand `{{key}}`, `{{desc}}` and `{{keysym}}` in `cmd`.
+        #[serde(default)]
+        placeholders: bool,
+        /// Wait for the command and show its output in the menu.
+        #[serde(default)]
+        capture: bool,
+        when: Option<String>,
+        /// Show the entry, but do not allow selecting it.
+        #[serde(default)]
+        disabled: bool,
+        /// Send a desktop notification when the entry is selected.
+        notify: Option<Notify>,
+        /// Text shown in the key column instead of the key.
+        key_label: Option<String>,
+        /// Run the command again while the key is held, if the menu stays open.
+        #[serde(default)]
+        repeat: bool,
+        /// Wait for the command, then update the `desc_cmd` descriptions of the current page.
         #[serde(default)]
-        keep_open: bool,
+        refresh: bool,
     },
     Recursive {
         submenu: Entries,
+        #[serde(deserialize_with = "deserialize_desc")]
         desc: String,
+        #[serde(default)]
+        desc_cmd: bool,
+        when: Option<String>,
+        #[serde(default)]
+        disabled: bool,
+        key_label: Option<String>,
+        /// The order of the entries of the submenu, defaults to the order of this page.
+        sort: Option<Sort>,
     },
+    /// A non-selectable row: a label, or a horizontal line if the label is `~`. Only allowed
+    /// under keys starting with `---`.
+    Separator {
+        /// Required, so that other entries with missing fields are not taken for separators.
+        #[serde(deserialize_with = "deserialize_separator")]
+        separator: Option<String>,
+        when: Option<String>,
+    },
+}
+
+impl Entry {
+    /// The command which must succeed for this entry to be shown.
+    pub fn when(&self) -> Option<&str> {
+        match self {
+            Self::Cmd { when, .. }
+            | Self::Recursive { when, .. }
+            | Self::Separator { when, .. } => when.as_deref(),
+        }
+    }
+}
+
+/// A desktop notification: either `true` to use the description of the entry, or a message.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Notify {
+    Enabled(bool),
+    Message(String),
+}
+
+/// Whether the menu stays open after running a command: either a bool, or `{ max: N }` to close
+/// it after N activations.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum KeepOpen {
+    Enabled(bool),
+    Max { max: u32 },
+}
+
+impl Default for KeepOpen {
+    fn default() -> Self {
+        Self::Enabled(false)
+    }
+}
+
+/// What happens after the command of an entry is executed.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum Then {
+    /// Stay on the current page.
+    Stay,
+    /// Close the menu.
+    Quit,
+    /// Go back to the parent page, or close the menu on the root page.
+    Parent,
+    /// Go back to the root page.
+    Root,
+}
+
+impl Then {
+    /// Whether the menu stays on the current page after running the command, given whether the
+    /// page is the top-level one. A `sticky` modifier keeps the menu open instead of closing it.
+    pub fn stays(self, sticky: bool, is_root: bool) -> bool {
+        match self {
+            Self::Stay => true,
+            Self::Quit => sticky,
+            Self::Parent => sticky && is_root,
+            Self::Root => false,
+        }
+    }
+}
+
+/// Deserialize a command given either as a string or as a list of commands.
+fn deserialize_cmd<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Cmd {
+        Single(String),
+        Chain(Vec<String>),
+    }
+
+    match Cmd::deserialize(deserializer)? {
+        Cmd::Single(cmd) => Ok(vec![cmd]),
+        Cmd::Chain(cmds) if cmds.is_empty() => Err(de::Error::invalid_length(0, &"a command")),
+        Cmd::Chain(cmds) => Ok(cmds),
+    }
+}
+
+/// Deserialize the label of a separator, `None` for a horizontal line.
+fn deserialize_separator<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    Option::deserialize(deserializer)
+}
+
+/// Deserialize `fixed_size` given either as a bool or by name.
+fn deserialize_fixed_size<'de, D>(deserializer: D) -> Result<Option<FixedSize>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Enabled(bool),
+        Named(FixedSize),
+    }
+
+    Ok(match Raw::deserialize(deserializer)? {
+        Raw::Enabled(true) => Some(FixedSize::Largest),
+        Raw::Enabled(false) => None,
+        Raw::Named(fixed_size) => Some(fixed_size),
+    })
+}
+
+/// Deserialize a description given either as a string or as a list of lines.
+fn deserialize_desc<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Desc {
+        Text(String),
+        Lines(Vec<String>),
+    }
+
+    Ok(match Desc::deserialize(deserializer)? {
+        Desc::Text(text) => text,
+        Desc::Lines(lines) => lines.join("\n"),
+    })
 }
 
 impl Config {
-    pub fn new(name: &str) -> Result<Self> {
-        let mut config_path = config_dir().context("Cound not find config directory")?;
-        config_path.push("wlr-which-key");
-        config_path.push(name);
-        config_path.set_extension("yaml");
+    /// The paths where the config file with the given name is searched, in order of precedence.
+    pub fn candidate_paths(name: &str) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        for dir in config_dir().into_iter().chain(system_config_dirs()) {
+            let mut path = dir;
+            path.push("wlr-which-key");
+            path.push(name);
+            path.set_extension("yaml");
+            // An absolute name results in the same path for every directory
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
 
-        if !config_path.exists() {
-            bail!("config file not found: {}", config_path.display());
+    /// The path of the first existing config file with the given name.
+    pub fn path(name: &str) -> Result<PathBuf> {
+        let candidates = Self::candidate_paths(name);
+        if candidates.is_empty() {
+            bail!("Cound not find config directory");
         }
+        match candidates.iter().find(|path| path.exists()) {
+            Some(path) => Ok(path.clone()),
+            None => {
+                let searched: Vec<_> = candidates
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                bail!("config file not found, searched: {}", searched.join(", "));
+            }
+        }
+    }
 
-        let config = read_to_string(config_path).context("Failed to read configuration")?;
-        serde_yaml::from_str(&config).context("Failed to deserialize configuration")
+    /// Load the config file with the given name, or read the config from stdin if `name` is `-`.
+    pub fn new(name: &str) -> Result<Self> {
+        let config = if name == "-" {
+            io::read_to_string(io::stdin()).context("Failed to read configuration from stdin")?
+        } else {
+            read_to_string(Self::path(name)?).context("Failed to read configuration")?
+        };
+        config.parse()
     }
 
     pub fn padding(&self) -> f64 {
-        self.padding.unwrap_or(self.corner_r)
+        self.padding.unwrap_or(self.corner_r.max())
+    }
+
+    /// The keys which close the menu: either the configured ones or, if `implicit_cancel` is
+    /// enabled, `Escape`, `Ctrl+[` and `Ctrl+g`.
+    pub fn cancel_keys(&self) -> Vec<SingleKey> {
+        match &self.cancel_keys {
+            Some(keys) => keys.clone(),
+            None if self.implicit_cancel => vec![
+                SingleKey::new(xkb::Keysym::Escape, false),
+                SingleKey::new(xkb::Keysym::bracketleft, true),
+                SingleKey::new(xkb::Keysym::g, true),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    /// The placement of the window on the given output, with per-output overrides applied.
+    pub fn placement(&self, output: Option<&str>) -> Placement {
+        let overrides = output.and_then(|name| self.per_output.get(name));
+        let position = match overrides.and_then(|o| o.anchor) {
+            // An anchor override for this output takes precedence over the global position
+            Some(_) => overrides.and_then(|o| o.position),
+            None => overrides.and_then(|o| o.position).or(self.position),
+        };
+        if let Some(Position { x, y }) = position {
+            let (anchor, margin_top, margin_right, margin_bottom, margin_left) =
+                match (x < 0, y < 0) {
+                    (false, false) => (ConfigAnchor::TopLeft, y, 0, 0, x),
+                    (true, false) => (ConfigAnchor::TopRight, y, -x, 0, 0),
+                    (false, true) => (ConfigAnchor::BottomLeft, 0, 0, -y, x),
+                    (true, true) => (ConfigAnchor::BottomRight, 0, -x, -y, 0),
+                };
+            return Placement {
+                anchor,
+                margin_top,
+                margin_right,
+                margin_bottom,
+                margin_left,
+            };
+        }
+
+        let get =
+            |f: fn(&OutputConfig) -> Option<i32>, default| overrides.and_then(f).unwrap_or(default);
+        Placement {
+            anchor: overrides.and_then(|o| o.anchor).unwrap_or(self.anchor),
+            margin_top: get(|o| o.margin_top, self.margin_top),
+            margin_right: get(|o| o.margin_right, self.margin_right),
+            margin_bottom: get(|o| o.margin_bottom, self.margin_bottom),
+            margin_left: get(|o| o.margin_left, self.margin_left),
+        }
+    }
+
+    /// The font used for the key column.
+    pub fn key_font(&self) -> FontDescription {
+        if let Some(font) = &self.key_font {
+            return font.0.clone();
+        }
+        let mut font = self.font.0.clone();
+        if self.monospace_keys {
+            font.set_family("monospace");
+        }
+        font
+    }
+
+    /// The font used for the description column.
+    pub fn desc_font(&self) -> &FontDescription {
+        self.desc_font.as_ref().unwrap_or(&self.font)
+    }
+
+    /// The space around the window reserved for the drop shadow.
+    pub fn shadow_margin(&self) -> f64 {
+        let [offset_x, offset_y] = self.shadow_offset;
+        (self.shadow_blur.max(0.0) + offset_x.abs().max(offset_y.abs())).ceil()
+    }
+}
+
+/// Parse and validate a config, without reading any files.
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(config: &str) -> Result<Self> {
+        let mut config: Self =
+            serde_yaml::from_str(config).map_err(|e| deserialize_error(config, e))?;
+
+        let fonts = [
+            Some(&mut config.font),
+            config.key_font.as_mut(),
+            config.desc_font.as_mut(),
+        ];
+        for font in fonts.into_iter().flatten() {
+            if config.bold {
+                font.0.set_weight(pango::Weight::Bold);
+            }
+            if config.italic {
+                font.0.set_style(pango::Style::Italic);
+            }
+        }
+
+        check_separators(&config.menu)?;
+        if config.row_spacing < 0.0 {
+            bail!("row_spacing must not be negative");
+        }
+        if config.line_height <= 0.0 {
+            bail!("line_height must be positive");
+        }
+        if config.border_width < 0.0 || config.padding.is_some_and(|p| p < 0.0) {
+            bail!("border_width and padding must not be negative");
+        }
+        let r = config.corner_r;
+        if [r.top_left, r.top_right, r.bottom_right, r.bottom_left]
+            .iter()
+            .any(|r| *r < 0.0)
+        {
+            bail!("corner_r must not be negative");
+        }
+        if config.exclusive_zone.is_some() {
+            let outputs = iter::once(None).chain(config.per_output.keys().map(|k| Some(&**k)));
+            for output in outputs {
+                let anchor = config.placement(output).anchor;
+                if !matches!(
+                    anchor,
+                    ConfigAnchor::Top
+                        | ConfigAnchor::Bottom
+                        | ConfigAnchor::Left
+                        | ConfigAnchor::Right
+                ) {
+                    bail!("exclusive_zone requires anchor to be one of top, bottom, left or right");
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Check that separators are only used under keys starting with `---`, and that such keys are
+/// only used for separators.
+fn check_separators(entries: &Entries) -> Result<()> {
+    for (key, entry) in &entries.0 {
+        match entry {
+            Entry::Separator { .. } if !key.is_separator() => {
+                bail!(
+                    "`{}` is a separator, its key must start with `---`",
+                    key.repr
+                );
+            }
+            Entry::Cmd { .. } | Entry::Recursive { .. } if key.is_separator() => {
+                bail!(
+                    "`{}` must be a separator (like `{{ separator: Label }}`), keys starting \
+                     with `---` can not be selected",
+                    key.repr,
+                );
+            }
+            Entry::Recursive { submenu, .. } => check_separators(submenu)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// The file used to remember the last visited page of the menu with the given config name.
+pub fn last_page_file(name: &str) -> Option<PathBuf> {
+    let name = Path::new(name).file_stem()?;
+    let mut path = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".local/state")))?;
+    path.push("wlr-which-key");
+    path.push(name);
+    path.set_extension("last-page");
+    Some(path)
+}
+
+/// Explain why the config could not be deserialized. The location of the error is included in
+/// the message of `error`.
+fn deserialize_error(config: &str, error: serde_yaml::Error) -> anyhow::Error {
+    let error = anyhow::Error::new(error);
+    if menu_is_list(config) {
+        error.context(
+            "Failed to deserialize configuration: `menu` must be a mapping from keys to entries \
+             (like `p: { desc: Power, submenu: ... }`), not a list as in the old config format",
+        )
+    } else {
+        error.context("Failed to deserialize configuration")
+    }
+}
+
+/// Whether `menu` is given as a list, as in the old config format and other which-key tools.
+/// The lines are checked if the config is not even valid YAML.
+fn menu_is_list(config: &str) -> bool {
+    if let Ok(value) = serde_yaml::from_str::<serde_yaml::Value>(config) {
+        return value
+            .get("menu")
+            .is_some_and(serde_yaml::Value::is_sequence);
+    }
+
+    let mut lines = config
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.trim_start().is_empty() && !line.trim_start().starts_with('#'));
+    let Some(menu) = lines.find(|line| line.starts_with("menu:")) else {
+        return false;
+    };
+    match menu["menu:".len()..].trim_start() {
+        "" => lines
+            .next()
+            .is_some_and(|line| line.trim_start() == "-" || line.trim_start().starts_with("- ")),
+        value => value.starts_with('['),
+    }
+}
+
+/// The directories in `$XDG_CONFIG_DIRS`, or `/etc/xdg` if it is not set.
+fn system_config_dirs() -> Vec<PathBuf> {
+    match env::var_os("XDG_CONFIG_DIRS") {
+        Some(dirs) if !dirs.is_empty() => env::split_paths(&dirs)
+            .filter(|dir| dir.is_absolute())
+            .collect(),
+        _ => vec![PathBuf::from("/etc/xdg")],
     }
 }
 
@@ -131,6 +745,201 @@ impl<'de> de::Deserialize<'de> for Font {
     }
 }
 
+/// Radius of each of the four corners of the window.
+///
+/// Deserializes either from a single number, which is used for all corners, or from a list of
+/// four numbers in the `[top-left, top-right, bottom-right, bottom-left]` order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CornerRadius {
+    pub top_left: f64,
+    pub top_right: f64,
+    pub bottom_right: f64,
+    pub bottom_left: f64,
+}
+
+impl CornerRadius {
+    pub fn uniform(r: f64) -> Self {
+        Self {
+            top_left: r,
+            top_right: r,
+            bottom_right: r,
+            bottom_left: r,
+        }
+    }
+
+    /// The radius of a rectangle which is `by` units larger on every side, or smaller if `by` is
+    /// negative. Radii do not go below zero.
+    pub fn grow(self, by: f64) -> Self {
+        Self {
+            top_left: (self.top_left + by).max(0.0),
+            top_right: (self.top_right + by).max(0.0),
+            bottom_right: (self.bottom_right + by).max(0.0),
+            bottom_left: (self.bottom_left + by).max(0.0),
+        }
+    }
+
+    pub fn max(&self) -> f64 {
+        self.top_left
+            .max(self.top_right)
+            .max(self.bottom_right)
+            .max(self.bottom_left)
+    }
+
+    /// Scale the radii down, if needed, so that the corners of a `width`x`height` rectangle do
+    /// not overlap.
+    pub fn fit(self, width: f64, height: f64) -> Self {
+        let scale = [
+            width / (self.top_left + self.top_right),
+            width / (self.bottom_left + self.bottom_right),
+            height / (self.top_left + self.bottom_left),
+            height / (self.top_right + self.bottom_right),
+        ]
+        .into_iter()
+        .filter(|scale| scale.is_finite())
+        .fold(1.0, f64::min)
+        .max(0.0);
+        Self {
+            top_left: self.top_left * scale,
+            top_right: self.top_right * scale,
+            bottom_right: self.bottom_right * scale,
+            bottom_left: self.bottom_left * scale,
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for CornerRadius {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        struct CornerRadiusVisitor;
+
+        impl<'de> de::Visitor<'de> for CornerRadiusVisitor {
+            type Value = CornerRadius;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number or a list of four numbers")
+            }
+
+            fn visit_f64<E>(self, r: f64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(CornerRadius::uniform(r))
+            }
+
+            fn visit_i64<E>(self, r: i64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                self.visit_f64(r as f64)
+            }
+
+            fn visit_u64<E>(self, r: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                self.visit_f64(r as f64)
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: de::SeqAccess<'de>,
+            {
+                let mut next = |i| {
+                    seq.next_element::<f64>()?
+                        .ok_or_else(|| de::Error::invalid_length(i, &self))
+                };
+                let radius = CornerRadius {
+                    top_left: next(0)?,
+                    top_right: next(1)?,
+                    bottom_right: next(2)?,
+                    bottom_left: next(3)?,
+                };
+                if seq.next_element::<de::IgnoredAny>()?.is_some() {
+                    return Err(de::Error::invalid_length(5, &self));
+                }
+                Ok(radius)
+            }
+        }
+
+        deserializer.deserialize_any(CornerRadiusVisitor)
+    }
+}
+
+/// Horizontal alignment of text within its column.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl Align {
+    /// The offset of a `width` wide item aligned inside of `available` space.
+    pub fn offset(self, available: f64, width: f64) -> f64 {
+        match self {
+            Self::Left => 0.0,
+            Self::Center => (available - width) * 0.5,
+            Self::Right => available - width,
+        }
+    }
+}
+
+/// How the background image is fitted into the window.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum BackgroundImageFit {
+    /// Scale the image to cover the whole window, cropping it if necessary.
+    #[default]
+    Cover,
+    /// Scale the image to fit entirely inside the window.
+    Contain,
+    /// Repeat the image at its original size.
+    Tile,
+}
+
+/// How the entries of a page are arranged.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum Layout {
+    /// Split entries into columns according to `rows_per_column` or `auto_columns`.
+    #[default]
+    Grid,
+    /// Always show all entries in a single column.
+    #[serde(alias = "single_column")]
+    SingleColumn,
+}
+
+/// The order of the entries on a page.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum Sort {
+    /// The order of the config.
+    #[default]
+    None,
+    /// Sorted by key.
+    Key,
+    /// Sorted by description, ignoring case.
+    Desc,
+}
+
+/// The animation used when switching between pages.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum Transition {
+    /// Switch pages instantly.
+    #[default]
+    None,
+    /// Crossfade between the old and the new page.
+    Fade,
+    /// Slide the new page in, from the right when entering a submenu and from the left when
+    /// going back.
+    Slide,
+}
+
 /// Light wrapper around `Anchor` which also supports the "no anchor" value.
 ///
 /// This type is also requires to derive `Deserialize` for the foreign type.
@@ -147,13 +956,15 @@ pub enum ConfigAnchor {
     TopRight,
     BottomLeft,
     BottomRight,
+    /// At the pointer position, falls back to `center` if there is no pointer.
+    Cursor,
 }
 
 /// Convert this anchor into the type expected by `wayrs`.
 impl From<ConfigAnchor> for Anchor {
     fn from(value: ConfigAnchor) -> Self {
         match value {
-            ConfigAnchor::Center => Anchor::empty(),
+            ConfigAnchor::Center | ConfigAnchor::Cursor => Anchor::empty(),
             ConfigAnchor::Top => Anchor::Top,
             ConfigAnchor::Bottom => Anchor::Bottom,
             ConfigAnchor::Left => Anchor::Left,
@@ -165,3 +976,152 @@ impl From<ConfigAnchor> for Anchor {
         }
     }
 }
+
+/// The size of the exclusive zone: a number of pixels, or `auto` for the size of the menu.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum ExclusiveZone {
+    Fixed(i32),
+    Auto(AutoKeyword),
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum AutoKeyword {
+    Auto,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum FixedSize {
+    /// The largest width and the largest height of all pages.
+    Largest,
+}
+
+/// Wrapper around `Layer` which can be deserialized.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum ConfigLayer {
+    Background,
+    Bottom,
+    Top,
+    #[default]
+    Overlay,
+}
+
+impl From<ConfigLayer> for Layer {
+    fn from(value: ConfigLayer) -> Self {
+        match value {
+            ConfigLayer::Background => Layer::Background,
+            ConfigLayer::Bottom => Layer::Bottom,
+            ConfigLayer::Top => Layer::Top,
+            ConfigLayer::Overlay => Layer::Overlay,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(config: &str) -> String {
+        match Config::from_str(config) {
+            Ok(_) => panic!("{config:?} was accepted"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn old_format_errors() {
+        // An entry with a typo
+        let e = error("menu:\n  - key: p\n    desc: Power\n    submenu: []\n");
+        assert!(e.contains("not a list as in the old config format"), "{e}");
+        assert!(e.contains("line 2"), "{e}");
+        // Not valid YAML
+        let e = error("menu:\n  - key: p\n    desc: [Power\n");
+        assert!(e.contains("not a list as in the old config format"), "{e}");
+        let e = error("menu: [{ key: p, desc: Power }\n");
+        assert!(e.contains("not a list as in the old config format"), "{e}");
+    }
+
+    #[test]
+    fn new_format_errors() {
+        let e = error("menu:\n  p: { desc: Power, cmd: poweroff, keep_opne: true }\n");
+        assert!(!e.contains("old config format"), "{e}");
+        assert!(e.contains("line 2"), "{e}");
+        // Not valid YAML
+        let e = error("# Comment\nmenu:\n  p: { desc: [Power, cmd: poweroff }\n");
+        assert!(!e.contains("old config format"), "{e}");
+        assert!(e.contains("line 3"), "{e}");
+    }
+
+    #[test]
+    fn separators() {
+        let config = Config::from_str(
+            "menu:\n  --- media: { separator: Media }\n  --- line: { separator: ~ }\n  \
+             a: { desc: A, cmd: a }",
+        )
+        .unwrap();
+        let mut entries = config.menu.0.values();
+        assert!(matches!(
+            entries.next(),
+            Some(Entry::Separator { separator: Some(label), .. }) if label == "Media"
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(Entry::Separator {
+                separator: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn separator_must_be_given() {
+        // Not taken for a separator without a label, and never selectable
+        error("menu:\n  a: {}");
+        error("menu:\n  a: { when: 'true' }");
+    }
+
+    #[test]
+    fn separator_keys() {
+        let e = error("menu:\n  a: { separator: Media }");
+        assert!(e.contains("must start with `---`"), "{e}");
+        let e = error("menu:\n  ---: { desc: A, cmd: a }");
+        assert!(e.contains("must be a separator"), "{e}");
+        let e =
+            error("menu:\n  a:\n    desc: A\n    submenu:\n      --- x: { desc: B, submenu: {} }");
+        assert!(e.contains("must be a separator"), "{e}");
+    }
+
+    #[test]
+    fn corner_radius_fit() {
+        let r = CornerRadius::uniform(10.0);
+        assert_eq!(r.fit(100.0, 20.0), r);
+        assert_eq!(r.fit(100.0, 10.0), CornerRadius::uniform(5.0));
+        assert_eq!(r.fit(0.0, 100.0), CornerRadius::uniform(0.0));
+
+        // Scaled as a whole, so that the corners keep their proportions
+        let r = CornerRadius {
+            top_left: 30.0,
+            top_right: 10.0,
+            bottom_right: 0.0,
+            bottom_left: 10.0,
+        };
+        let fit = r.fit(20.0, 100.0);
+        assert_eq!(fit.top_left, 15.0);
+        assert_eq!(fit.top_right, 5.0);
+        assert_eq!(fit.bottom_right, 0.0);
+        assert_eq!(fit.bottom_left, 5.0);
+
+        // Square corners stay square
+        assert_eq!(
+            CornerRadius::uniform(0.0).fit(0.0, 0.0),
+            CornerRadius::uniform(0.0)
+        );
+        assert_eq!(
+            CornerRadius::uniform(1e9).fit(1.0, 1.0),
+            CornerRadius::uniform(0.5)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+
+use anyhow::Context;
+
+/// The well-known name and the interface of the service.
+pub const NAME: &str = "io.github.MaxVerevkin.WlrWhichKey";
+/// The object implementing the interface.
+pub const PATH: &str = "/io/github/MaxVerevkin/WlrWhichKey";
+
+/// A request to show a menu, made with the `ShowMenu` method.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ShowMenu {
+    /// The name of the config, as given on the command line. Empty for the default config.
+    pub config: String,
+    /// The keys of the submenus to open, as accepted by `Menu::navigate_to`.
+    pub path: Vec<String>,
+}
+
+/// The D-Bus service started with `--dbus`, which keeps the process running and shows menus
+/// when asked to.
+///
+/// Method calls are handled on the thread of the D-Bus connection, and passed on to the event
+/// loop. The service is readable (as a fd) when requests are pending.
+pub struct Service {
+    _conn: zbus::blocking::Connection,
+    requests: mpsc::Receiver<ShowMenu>,
+    wake: UnixStream,
+}
+
+impl Service {
+    /// Claim the name of the service on the session bus.
+    pub fn start() -> anyhow::Result<Self> {
+        let builder = zbus::blocking::connection::Builder::session()?.name(NAME)?;
+        Self::serve(builder)
+            .with_context(|| format!("failed to claim {NAME}, is another instance running?"))
+    }
+
+    /// Serve the interface on the connection built by `builder`.
+    fn serve(builder: zbus::blocking::connection::Builder) -> anyhow::Result<Self> {
+        let (sender, requests) = mpsc::channel();
+        let (wake, wake_sender) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        wake_sender.set_nonblocking(true)?;
+        let interface = Interface {
+            requests: sender,
+            wake: wake_sender,
+        };
+        let conn = builder.serve_at(PATH, interface)?.build()?;
+        Ok(Self {
+            _conn: conn,
+            requests,
+            wake,
+        })
+    }
+
+    /// The requests which arrived since the last call, oldest first.
+    pub fn take_requests(&self) -> Vec<ShowMenu> {
+        let mut buf = [0; 64];
+        while matches!((&self.wake).read(&mut buf), Ok(n) if n > 0) {}
+        self.requests.try_iter().collect()
+    }
+}
+
+impl AsRawFd for Service {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+}
+
+struct Interface {
+    requests: mpsc::Sender<ShowMenu>,
+    wake: UnixStream,
+}
+
+#[zbus::interface(name = "io.github.MaxVerevkin.WlrWhichKey")]
+impl Interface {
+    /// Show the menu with the config `config` (the default one if empty), with the submenu at
+    /// `key_path` open. A menu which is already shown is replaced.
+    fn show_menu(&self, config: String, key_path: Vec<String>) -> zbus::fdo::Result<()> {
+        let request = ShowMenu {
+            config,
+            path: key_path,
+        };
+        if self.requests.send(request).is_err() {
+            return Err(zbus::fdo::Error::Failed("the service is stopping".into()));
+        }
+        match (&self.wake).write_all(&[0]) {
+            // The event loop has not read the previous wake-ups yet
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map_err(|e| zbus::fdo::Error::IOError(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use zbus::blocking::connection::Builder;
+
+    #[test]
+    // The replacement takes an `async_io::Async<UnixStream>`, not worth a dependency here
+    #[allow(deprecated)]
+    fn show_menu() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let guid = zbus::Guid::generate();
+        // Both ends wait for the handshake while connecting
+        let service = std::thread::spawn(move || {
+            Service::serve(Builder::unix_stream(server).server(guid).unwrap().p2p())
+        });
+        let client = Builder::unix_stream(client).p2p().build().unwrap();
+        let service = service.join().unwrap().unwrap();
+
+        for (config, path) in [
+            ("", vec![]),
+            ("power", vec!["s".to_owned(), "r".to_owned()]),
+        ] {
+            client
+                .call_method(None::<&str>, PATH, Some(NAME), "ShowMenu", &(config, &path))
+                .unwrap();
+        }
+        // The requests were passed on before the calls returned
+        assert_eq!(
+            service.take_requests(),
+            [
+                ShowMenu {
+                    config: String::new(),
+                    path: vec![],
+                },
+                ShowMenu {
+                    config: "power".to_owned(),
+                    path: vec!["s".to_owned(), "r".to_owned()],
+                },
+            ]
+        );
+        assert!(service.take_requests().is_empty());
+    }
+}
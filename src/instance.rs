@@ -0,0 +1,134 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// The message sent to a running instance to close it.
+const CLOSE: &str = "close";
+
+/// A socket which marks the menu with a given config as open.
+///
+/// Starting the same menu again while it is open closes it instead (unless disabled with
+/// `--no-toggle`), so that pressing the key bound to the menu twice toggles it. Menus with
+/// different configs do not affect each other.
+pub struct InstanceGuard {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl InstanceGuard {
+    /// Take over the socket for the config `name`. If another instance is running, ask it to
+    /// close if `toggle` is set, and return `None`.
+    pub fn acquire(name: &str, toggle: bool) -> io::Result<Option<Self>> {
+        Self::acquire_at(socket_path(name), toggle)
+    }
+
+    fn acquire_at(path: PathBuf, toggle: bool) -> io::Result<Option<Self>> {
+        // Without the lock, two instances started at once could both fail to connect, and the
+        // second one would remove the socket of the first one
+        let _lock = lock(&path.with_extension("lock"))?;
+
+        if let Ok(mut stream) = UnixStream::connect(&path) {
+            if toggle {
+                writeln!(stream, "{CLOSE}")?;
+            }
+            return Ok(None);
+        }
+
+        // Nobody is listening, so the socket (if any) was left behind by a crashed instance
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Some(Self { listener, path }))
+    }
+
+    /// Handle pending connections, returning whether any of them asked to close the menu.
+    pub fn close_requested(&self) -> bool {
+        let mut close = false;
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(false).is_err() {
+                continue;
+            }
+            let mut line = String::new();
+            if BufReader::new(stream).read_line(&mut line).is_ok() && line.trim() == CLOSE {
+                close = true;
+            }
+        }
+        close
+    }
+}
+
+impl AsRawFd for InstanceGuard {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for InstanceGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Take an exclusive lock on the file at `path`, waiting for other instances to release it. The
+/// lock is released when the file is closed.
+fn lock(path: &Path) -> io::Result<File> {
+    let file = File::options().create(true).append(true).open(path)?;
+    loop {
+        // Safety: the fd is open for as long as `file` lives
+        match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            _ => return Ok(file),
+        }
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/wlr-which-key-<config>.sock`, or the same file in the temporary directory.
+fn socket_path(name: &str) -> PathBuf {
+    let name = Path::new(name)
+        .file_stem()
+        .map_or("config".into(), |stem| stem.to_string_lossy());
+    let dir = env::var_os("XDG_RUNTIME_DIR").map_or_else(env::temp_dir, PathBuf::from);
+    dir.join(format!("wlr-which-key-{name}.sock"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn one_instance() {
+        let path = env::temp_dir().join(format!("wlr-which-key-test-{}.sock", std::process::id()));
+        let barrier = Arc::new(Barrier::new(8));
+        let started: Vec<_> = (0..8)
+            .map(|_| {
+                let (path, barrier) = (path.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    InstanceGuard::acquire_at(path, false).unwrap()
+                })
+            })
+            .collect();
+        let instances: Vec<_> = started
+            .into_iter()
+            .filter_map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(instances.len(), 1);
+        assert!(!instances[0].close_requested());
+
+        assert!(InstanceGuard::acquire_at(path.clone(), true)
+            .unwrap()
+            .is_none());
+        assert!(instances[0].close_requested());
+
+        drop(instances);
+        assert!(!path.exists());
+        let _ = fs::remove_file(path.with_extension("lock"));
+    }
+}
@@ -1,13 +1,62 @@
+use std::env;
 use std::fmt;
 use std::str::FromStr;
 
-use serde::de;
+use serde::{de, Deserialize};
 use wayrs_utils::keyboard::xkb;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+/// A key binding: one or more keys which must be pressed in order.
+///
+/// The special `<any>` binding has no keys and matches any otherwise unbound key which types a
+/// character. Names starting with `---` have no keys either and never match, they are used for
+/// separator entries.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Key {
-    pub keysym: xkb::Keysym,
+    pub keys: Vec<SingleKey>,
     pub repr: String,
+}
+
+/// The name of the catch-all binding.
+pub const WILDCARD: &str = "<any>";
+
+impl Key {
+    pub fn is_wildcard(&self) -> bool {
+        self.repr == WILDCARD
+    }
+
+    /// Whether this is the key of a separator entry.
+    pub fn is_separator(&self) -> bool {
+        self.repr.starts_with("---")
+    }
+
+    /// This key with every letter converted to lowercase.
+    pub fn to_lowercase(&self) -> Self {
+        Self {
+            keys: self.keys.iter().map(SingleKey::to_lowercase).collect(),
+            repr: self.repr.clone(),
+        }
+    }
+
+    /// The text shown in the key column: the key as written in the config or, if `pretty` or a
+    /// modifier style is set, built from the parsed keys. With `pretty`, special keys are replaced
+    /// by compact symbols.
+    pub fn label(&self, pretty: bool, modifier_style: Option<ModifierStyle>) -> String {
+        if (!pretty && modifier_style.is_none()) || self.keys.is_empty() {
+            return self.repr.clone();
+        }
+        let modifier_style = modifier_style.unwrap_or_default();
+        self.keys
+            .iter()
+            .map(|key| key.label(pretty, modifier_style))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// A single key press with modifiers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SingleKey {
+    pub keysym: xkb::Keysym,
     pub mod_ctrl: bool,
     pub mod_alt: bool,
 }
@@ -15,10 +64,36 @@ pub struct Key {
 impl FromStr for Key {
     type Err = String;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // A single space is a valid key on its own
+        let keys = if s == WILDCARD || s.starts_with("---") {
+            Vec::new()
+        } else if s.trim().is_empty() {
+            vec![s.parse()?]
+        } else {
+            s.split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?
+        };
+
+        Ok(Self {
+            keys,
+            repr: s.to_owned(),
+        })
+    }
+}
+
+impl FromStr for SingleKey {
+    type Err = String;
+
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut components = s.split('+');
         let key = components.next_back().unwrap_or(s);
-        let keysym = to_keysym(key).ok_or_else(|| format!("invalid key '{key}'"))?;
+        let keysym = to_keysym(key).ok_or_else(|| {
+            format!(
+                "invalid key '{key}' (run `xkbcli interactive-wayland` and press the key to see its name)"
+            )
+        })?;
 
         let mut mod_ctrl = false;
         let mut mod_alt = false;
@@ -28,19 +103,327 @@ impl FromStr for Key {
             } else if modifier.eq_ignore_ascii_case("alt") {
                 mod_alt = true;
             } else {
-                return Err(format!("unknown modifier '{modifier}"));
+                return Err(format!("unknown modifier '{modifier}'"));
             }
         }
 
         Ok(Self {
             keysym,
-            repr: s.to_owned(),
             mod_ctrl,
             mod_alt,
         })
     }
 }
 
+impl SingleKey {
+    pub fn new(keysym: xkb::Keysym, mod_ctrl: bool) -> Self {
+        Self {
+            keysym,
+            mod_ctrl,
+            mod_alt: false,
+        }
+    }
+
+    /// This key with the keysym converted to lowercase, if it is a letter.
+    pub fn to_lowercase(&self) -> Self {
+        Self {
+            keysym: keysym_to_lowercase(self.keysym),
+            ..self.clone()
+        }
+    }
+
+    /// The key with its modifiers, using a compact symbol for special keys if `pretty` is set.
+    fn label(&self, pretty: bool, modifier_style: ModifierStyle) -> String {
+        let mut label = String::new();
+        let modifiers = [
+            (self.mod_ctrl, Modifier::Ctrl),
+            (self.mod_alt, Modifier::Alt),
+        ];
+        for (_, modifier) in modifiers.into_iter().filter(|(active, _)| *active) {
+            label.push_str(modifier_style.prefix(modifier));
+        }
+
+        let symbol = pretty_keysym(self.keysym).filter(|_| pretty);
+        match symbol {
+            Some(symbol) => label.push_str(symbol),
+            None => match self.keysym.key_char().filter(|c| !c.is_control()) {
+                Some(c) => label.push(c),
+                None => label.push_str(&xkb::keysym_get_name(self.keysym)),
+            },
+        }
+        label
+    }
+
+    /// The key press corresponding to `keysym` in the given state.
+    pub fn pressed(xkb: &xkb::State, keysym: xkb::Keysym) -> Self {
+        Self {
+            keysym,
+            mod_ctrl: xkb.mod_name_is_active(xkb::MOD_NAME_CTRL, xkb::STATE_MODS_EFFECTIVE),
+            mod_alt: xkb.mod_name_is_active(xkb::MOD_NAME_ALT, xkb::STATE_MODS_EFFECTIVE),
+        }
+    }
+}
+
+/// Which keyboard layouts are used to resolve key presses.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum LayoutMode {
+    /// Only the currently active layout.
+    #[default]
+    Active,
+    /// The active layout first, then every other layout of the keymap.
+    Any,
+}
+
+/// A modifier which can be referred to by name in the config.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum Modifier {
+    Shift,
+    #[serde(alias = "control")]
+    Ctrl,
+    #[serde(alias = "mod1")]
+    Alt,
+    #[serde(alias = "mod4", alias = "logo")]
+    Super,
+}
+
+/// How modifiers are shown in the key column.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all(deserialize = "lowercase"))]
+pub enum ModifierStyle {
+    /// `Ctrl+Alt+r`
+    #[default]
+    Verbose,
+    /// `C-M-r`
+    Emacs,
+    /// `⌃⌥r`
+    Symbolic,
+}
+
+impl ModifierStyle {
+    fn prefix(self, modifier: Modifier) -> &'static str {
+        match (self, modifier) {
+            (Self::Verbose, Modifier::Shift) => "Shift+",
+            (Self::Verbose, Modifier::Ctrl) => "Ctrl+",
+            (Self::Verbose, Modifier::Alt) => "Alt+",
+            (Self::Verbose, Modifier::Super) => "Super+",
+            (Self::Emacs, Modifier::Shift) => "S-",
+            (Self::Emacs, Modifier::Ctrl) => "C-",
+            (Self::Emacs, Modifier::Alt) => "M-",
+            (Self::Emacs, Modifier::Super) => "s-",
+            (Self::Symbolic, Modifier::Shift) => "⇧",
+            (Self::Symbolic, Modifier::Ctrl) => "⌃",
+            (Self::Symbolic, Modifier::Alt) => "⌥",
+            (Self::Symbolic, Modifier::Super) => "◆",
+        }
+    }
+}
+
+impl Modifier {
+    pub fn xkb_name(self) -> &'static str {
+        match self {
+            Self::Shift => xkb::MOD_NAME_SHIFT,
+            Self::Ctrl => xkb::MOD_NAME_CTRL,
+            Self::Alt => xkb::MOD_NAME_ALT,
+            Self::Super => xkb::MOD_NAME_LOGO,
+        }
+    }
+
+    pub fn is_active(self, xkb: &xkb::State) -> bool {
+        xkb.mod_name_is_active(self.xkb_name(), xkb::STATE_MODS_EFFECTIVE)
+    }
+
+    /// Whether `keysym` is one of the keys which set this modifier.
+    pub fn is_set_by(self, keysym: xkb::Keysym) -> bool {
+        use xkb::Keysym as K;
+        let keysyms: &[K] = match self {
+            Self::Shift => &[K::Shift_L, K::Shift_R],
+            Self::Ctrl => &[K::Control_L, K::Control_R],
+            Self::Alt => &[K::Alt_L, K::Alt_R, K::Meta_L, K::Meta_R],
+            Self::Super => &[K::Super_L, K::Super_R],
+        };
+        keysyms.contains(&keysym)
+    }
+}
+
+/// The state and keysym used to match a press of `keycode`, whose keysym in `xkb` is `keysym`.
+/// The `ignored` modifier is cleared, and so is CapsLock if `ignore_lock` is set, so that it does
+/// not turn a press of `a` into `A`.
+pub fn match_state(
+    xkb: xkb::State,
+    keycode: xkb::Keycode,
+    keysym: xkb::Keysym,
+    ignored: Option<Modifier>,
+    ignore_lock: bool,
+) -> (xkb::State, xkb::Keysym) {
+    let mut ignored_mods: Vec<&str> = ignored.map(Modifier::xkb_name).into_iter().collect();
+    if ignore_lock && xkb.mod_name_is_active(xkb::MOD_NAME_CAPS, xkb::STATE_MODS_EFFECTIVE) {
+        ignored_mods.push(xkb::MOD_NAME_CAPS);
+    }
+    if ignored_mods.is_empty() {
+        return (xkb, keysym);
+    }
+    let xkb = clear_mods(&xkb, &ignored_mods);
+    let keysym = xkb.key_get_one_sym(keycode);
+    (xkb, keysym)
+}
+
+/// The keysym of the main keys which types the same character as the keypad keysym `keysym`,
+/// e.g. `1` for `KP_1` and `Return` for `KP_Enter`. Other keysyms, and keypad keys which do not
+/// type a character (like `KP_End` when NumLock is off), are returned unchanged.
+pub fn numpad_to_main(keysym: xkb::Keysym) -> xkb::Keysym {
+    match keysym.is_keypad_key().then(|| keysym.key_char()).flatten() {
+        Some(c) => xkb::utf32_to_keysym(c as u32),
+        None => keysym,
+    }
+}
+
+/// Look for a layout other than the active one in which `keycode` produces a bound keysym.
+/// Returns the state with that layout active and the keysym.
+pub fn find_in_other_layouts(
+    xkb: &xkb::State,
+    keycode: xkb::Keycode,
+    is_bound: impl Fn(&xkb::State, xkb::Keysym) -> bool,
+) -> Option<(xkb::State, xkb::Keysym)> {
+    let active = xkb.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE);
+    (0..xkb.get_keymap().num_layouts())
+        .filter(|&layout| layout != active)
+        .map(|layout| {
+            let state = with_layout(xkb, layout);
+            let keysym = state.key_get_one_sym(keycode);
+            (state, keysym)
+        })
+        .find(|(state, keysym)| is_bound(state, *keysym))
+}
+
+/// Get a copy of `xkb` with the given modifiers cleared, keeping the active layout.
+pub fn clear_mods(xkb: &xkb::State, mods: &[&str]) -> xkb::State {
+    let keymap = xkb.get_keymap();
+    let mask = mods
+        .iter()
+        .map(|name| keymap.mod_get_index(*name))
+        .filter(|&idx| idx != xkb::MOD_INVALID)
+        .fold(0, |mask, idx| mask | (1 << idx));
+
+    let mut state = xkb::State::new(&keymap);
+    state.update_mask(
+        xkb.serialize_mods(xkb::STATE_MODS_DEPRESSED) & !mask,
+        xkb.serialize_mods(xkb::STATE_MODS_LATCHED) & !mask,
+        xkb.serialize_mods(xkb::STATE_MODS_LOCKED) & !mask,
+        0,
+        0,
+        xkb.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE),
+    );
+    state
+}
+
+/// Get a copy of `xkb` with the given layout active, keeping the modifiers.
+pub fn with_layout(xkb: &xkb::State, layout: xkb::LayoutIndex) -> xkb::State {
+    let mut state = xkb::State::new(&xkb.get_keymap());
+    state.update_mask(
+        xkb.serialize_mods(xkb::STATE_MODS_DEPRESSED),
+        xkb.serialize_mods(xkb::STATE_MODS_LATCHED),
+        xkb.serialize_mods(xkb::STATE_MODS_LOCKED),
+        0,
+        0,
+        layout,
+    );
+    state
+}
+
+/// A compact symbol for special keys, which would otherwise be shown by their long names.
+fn pretty_keysym(keysym: xkb::Keysym) -> Option<&'static str> {
+    use xkb::Keysym as K;
+    Some(match keysym {
+        K::BackSpace => "⌫",
+        K::Return | K::KP_Enter => "↵",
+        K::Tab => "⇥",
+        K::ISO_Left_Tab => "⇤",
+        K::Escape => "Esc",
+        K::space => "␣",
+        K::Delete => "⌦",
+        K::Insert => "Ins",
+        K::Up => "↑",
+        K::Down => "↓",
+        K::Left => "←",
+        K::Right => "→",
+        K::Home => "⇱",
+        K::End => "⇲",
+        K::Page_Up => "⇞",
+        K::Page_Down => "⇟",
+        K::Caps_Lock => "⇪",
+        K::Print => "PrtSc",
+        _ => return None,
+    })
+}
+
+/// A compose state for the current locale, if there is a compose table for it.
+pub fn compose_state() -> Option<xkb::compose::State> {
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .into_iter()
+        .find_map(|var| env::var_os(var).filter(|value| !value.is_empty()))
+        .unwrap_or_else(|| "C".into());
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let table =
+        xkb::compose::Table::new_from_locale(&context, &locale, xkb::compose::COMPILE_NO_FLAGS)
+            .ok()?;
+    Some(xkb::compose::State::new(
+        &table,
+        xkb::compose::STATE_NO_FLAGS,
+    ))
+}
+
+/// Combine `keysym` with the dead keys and compose sequence keys pressed before it. Returns the
+/// keysym to match, or `None` if the key press is ignored: while a sequence is incomplete, when
+/// it is cancelled, and for keys without a symbol. Bound keys are matched on their own and end any
+/// pending sequence, so that it does not combine with later keys.
+pub fn compose(
+    compose: Option<&mut xkb::compose::State>,
+    keysym: xkb::Keysym,
+    bound: bool,
+) -> Option<xkb::Keysym> {
+    // Keys without a symbol do not interrupt a key sequence
+    if keysym.raw() == xkb::keysyms::KEY_NoSymbol {
+        return None;
+    }
+    let Some(compose) = compose else {
+        return Some(keysym);
+    };
+    if bound {
+        compose.reset();
+        return Some(keysym);
+    }
+    // Modifiers are not part of sequences
+    if compose.feed(keysym) != xkb::compose::FeedResult::Accepted {
+        return Some(keysym);
+    }
+    match compose.status() {
+        xkb::compose::Status::Nothing => Some(keysym),
+        xkb::compose::Status::Composing => None,
+        xkb::compose::Status::Composed => {
+            let composed = compose.keysym();
+            compose.reset();
+            composed
+        }
+        xkb::compose::Status::Cancelled => {
+            compose.reset();
+            None
+        }
+    }
+}
+
+fn keysym_to_lowercase(keysym: xkb::Keysym) -> xkb::Keysym {
+    let Some(c) = keysym.key_char() else {
+        return keysym;
+    };
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(l), None) if l != c => xkb::Keysym::from_char(l),
+        _ => keysym,
+    }
+}
+
 fn to_keysym(s: &str) -> Option<xkb::Keysym> {
     let mut chars = s.chars();
     let first_char = chars.next()?;
@@ -48,7 +431,14 @@ fn to_keysym(s: &str) -> Option<xkb::Keysym> {
     let keysym = if chars.next().is_none() {
         xkb::utf32_to_keysym(first_char as u32)
     } else {
-        xkb::keysym_from_name(s, xkb::KEYSYM_NO_FLAGS)
+        // Prefer the exact name, so that e.g. `Eacute` and `eacute` stay distinct, but also
+        // accept names like `return` or `xf86audiomute`.
+        match xkb::keysym_from_name(s, xkb::KEYSYM_NO_FLAGS) {
+            keysym if keysym.raw() == xkb::keysyms::KEY_NoSymbol => {
+                xkb::keysym_from_name(s, xkb::KEYSYM_CASE_INSENSITIVE)
+            }
+            keysym => keysym,
+        }
     };
 
     if keysym.raw() == xkb::keysyms::KEY_NoSymbol {
@@ -65,9 +455,62 @@ impl<'de> de::Deserialize<'de> for Key {
     {
         struct KeyVisitor;
 
-        impl de::Visitor<'_> for KeyVisitor {
+        impl<'de> de::Visitor<'de> for KeyVisitor {
             type Value = Key;
 
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("key or a sequence of keys")
+            }
+
+            fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                s.parse().map_err(E::custom)
+            }
+
+            // Unquoted digits are numbers in YAML
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: de::SeqAccess<'de>,
+            {
+                let mut keys = Vec::new();
+                let mut repr = Vec::new();
+                while let Some(key) = seq.next_element::<String>()? {
+                    keys.push(key.parse().map_err(de::Error::custom)?);
+                    repr.push(key);
+                }
+                if keys.is_empty() {
+                    return Err(de::Error::invalid_length(0, &self));
+                }
+                Ok(Key {
+                    keys,
+                    repr: repr.join(" "),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(KeyVisitor)
+    }
+}
+
+impl<'de> de::Deserialize<'de> for SingleKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        struct SingleKeyVisitor;
+
+        impl de::Visitor<'_> for SingleKeyVisitor {
+            type Value = SingleKey;
+
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
                 formatter.write_str("key")
             }
@@ -80,6 +523,354 @@ impl<'de> de::Deserialize<'de> for Key {
             }
         }
 
-        deserializer.deserialize_str(KeyVisitor)
+        deserializer.deserialize_str(SingleKeyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(s: &str) -> Key {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn wildcard() {
+        assert!(key("<any>").is_wildcard());
+        assert!(key("<any>").keys.is_empty());
+        // `*` binds the asterisk key, as it always did
+        assert!(!key("*").is_wildcard());
+        assert_eq!(
+            key("*").keys,
+            [SingleKey::new(xkb::Keysym::asterisk, false)]
+        );
+    }
+
+    #[test]
+    fn key_names() {
+        use xkb::Keysym as K;
+        let names = [
+            ("a", K::a),
+            ("A", K::A),
+            (" ", K::space),
+            ("Return", K::Return),
+            ("Escape", K::Escape),
+            ("Print", K::Print),
+            ("Pause", K::Pause),
+            ("Insert", K::Insert),
+            ("F1", K::F1),
+            ("F12", K::F12),
+            ("XF86AudioMute", K::XF86_AudioMute),
+            ("XF86AudioRaiseVolume", K::XF86_AudioRaiseVolume),
+            ("XF86AudioLowerVolume", K::XF86_AudioLowerVolume),
+            ("XF86AudioPlay", K::XF86_AudioPlay),
+            ("XF86AudioNext", K::XF86_AudioNext),
+            ("XF86MonBrightnessUp", K::XF86_MonBrightnessUp),
+        ];
+        for (name, keysym) in names {
+            assert_eq!(key(name).keys, [SingleKey::new(keysym, false)], "{name:?}");
+        }
+    }
+
+    #[test]
+    fn key_names_case_insensitive() {
+        use xkb::Keysym as K;
+        let names = [
+            ("return", K::Return),
+            ("RETURN", K::Return),
+            ("escape", K::Escape),
+            ("print", K::Print),
+            ("f5", K::F5),
+            ("xf86audiomute", K::XF86_AudioMute),
+            ("XF86AUDIORAISEVOLUME", K::XF86_AudioRaiseVolume),
+        ];
+        for (name, keysym) in names {
+            assert_eq!(key(name).keys, [SingleKey::new(keysym, false)], "{name:?}");
+        }
+
+        // An exact match wins over a case-insensitive one
+        assert_eq!(key("Eacute").keys, [SingleKey::new(K::Eacute, false)]);
+        assert_eq!(key("eacute").keys, [SingleKey::new(K::eacute, false)]);
+        // Single characters keep their case
+        assert_ne!(key("a"), key("A"));
+    }
+
+    #[test]
+    fn modifiers() {
+        let key = key("Ctrl+Alt+XF86AudioMute");
+        assert_eq!(
+            key.keys,
+            [SingleKey {
+                keysym: xkb::Keysym::XF86_AudioMute,
+                mod_ctrl: true,
+                mod_alt: true,
+            }]
+        );
+        assert!("Hyper+a".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn no_modifier_style() {
+        assert_eq!(key("Alt+Ctrl+r").label(false, None), "Alt+Ctrl+r");
+        assert_eq!(
+            key("<any>").label(false, Some(ModifierStyle::Emacs)),
+            "<any>"
+        );
+    }
+
+    #[test]
+    fn verbose_modifier_style() {
+        let style = Some(ModifierStyle::Verbose);
+        assert_eq!(key("Alt+Ctrl+r").label(false, style), "Ctrl+Alt+r");
+        assert_eq!(key("Ctrl+x Ctrl+s").label(false, style), "Ctrl+x Ctrl+s");
+        assert_eq!(key("Ctrl+Return").label(false, style), "Ctrl+Return");
+        assert_eq!(key("Ctrl+Return").label(true, style), "Ctrl+↵");
+    }
+
+    #[test]
+    fn emacs_modifier_style() {
+        let style = Some(ModifierStyle::Emacs);
+        assert_eq!(key("Alt+Ctrl+r").label(false, style), "C-M-r");
+        assert_eq!(key("Ctrl+x Ctrl+s").label(false, style), "C-x C-s");
+        assert_eq!(key("Alt+Return").label(false, style), "M-Return");
+        assert_eq!(key("r").label(false, style), "r");
+    }
+
+    #[test]
+    fn symbolic_modifier_style() {
+        let style = Some(ModifierStyle::Symbolic);
+        assert_eq!(key("Alt+Ctrl+r").label(false, style), "⌃⌥r");
+        assert_eq!(key("Ctrl+x Ctrl+s").label(false, style), "⌃x ⌃s");
+        assert_eq!(key("Alt+Return").label(true, style), "⌥↵");
+        assert_eq!(key("r").label(false, style), "r");
+    }
+
+    #[test]
+    fn invalid_key_names() {
+        for name in ["NotAKey", "XF86NotAKey", "Ctrl+NotAKey", "a NotAKey"] {
+            let error = name.parse::<Key>().unwrap_err();
+            assert!(
+                error.contains("'NotAKey'") || error.contains("'XF86NotAKey'"),
+                "{error}"
+            );
+            assert!(error.contains("xkbcli"), "{error}");
+        }
+    }
+
+    fn keymap(layouts: &str) -> xkb::Keymap {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        xkb::Keymap::new_from_names(
+            &context,
+            "",
+            "",
+            layouts,
+            "",
+            None,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .expect("failed to compile the keymap")
+    }
+
+    /// A state of `keymap` with the `depressed` and `locked` modifiers, and `layout` active.
+    fn state(
+        keymap: &xkb::Keymap,
+        depressed: &[&str],
+        locked: &[&str],
+        layout: xkb::LayoutIndex,
+    ) -> xkb::State {
+        let mask = |mods: &[&str]| {
+            mods.iter()
+                .fold(0, |mask, name| mask | (1 << keymap.mod_get_index(*name)))
+        };
+        let mut state = xkb::State::new(keymap);
+        state.update_mask(mask(depressed), 0, mask(locked), 0, 0, layout);
+        state
+    }
+
+    /// The key press matched for `key` (an xkb key name like `AC01`) in `state`.
+    fn press(
+        state: &xkb::State,
+        key: &str,
+        ignored: Option<Modifier>,
+        ignore_lock: bool,
+    ) -> SingleKey {
+        let keycode = state.get_keymap().key_by_name(key).unwrap();
+        let keysym = state.key_get_one_sym(keycode);
+        let (state, keysym) = match_state(state.clone(), keycode, keysym, ignored, ignore_lock);
+        SingleKey::pressed(&state, keysym)
+    }
+
+    #[test]
+    fn caps_lock_is_ignored() {
+        let keymap = keymap("us");
+        let caps_lock = state(&keymap, &[], &[xkb::MOD_NAME_CAPS], 0);
+        assert_eq!(press(&caps_lock, "AC01", None, true), key("a").keys[0]);
+        assert_eq!(press(&caps_lock, "AC01", None, false), key("A").keys[0]);
+        // Digits and other modifiers are not affected
+        assert_eq!(press(&caps_lock, "AE01", None, true), key("1").keys[0]);
+        let ctrl_caps_lock = state(&keymap, &[xkb::MOD_NAME_CTRL], &[xkb::MOD_NAME_CAPS], 0);
+        assert_eq!(
+            press(&ctrl_caps_lock, "AC01", None, true),
+            key("Ctrl+a").keys[0]
+        );
+    }
+
+    #[test]
+    fn shift_is_kept() {
+        let keymap = keymap("us");
+        let shift = state(&keymap, &[xkb::MOD_NAME_SHIFT], &[], 0);
+        assert_eq!(press(&shift, "AC01", None, true), key("A").keys[0]);
+        let shift_caps_lock = state(&keymap, &[xkb::MOD_NAME_SHIFT], &[xkb::MOD_NAME_CAPS], 0);
+        assert_eq!(
+            press(&shift_caps_lock, "AC01", None, true),
+            key("A").keys[0]
+        );
+    }
+
+    #[test]
+    fn sticky_modifier_is_ignored() {
+        let keymap = keymap("us");
+        let alt = state(&keymap, &[xkb::MOD_NAME_ALT], &[], 0);
+        assert_eq!(press(&alt, "AC01", None, true), key("Alt+a").keys[0]);
+        assert_eq!(
+            press(&alt, "AC01", Some(Modifier::Alt), true),
+            key("a").keys[0]
+        );
+    }
+
+    #[test]
+    fn keypad_names() {
+        use xkb::Keysym as K;
+        for (name, keysym) in [
+            ("KP_1", K::KP_1),
+            ("KP_Enter", K::KP_Enter),
+            ("KP_Add", K::KP_Add),
+        ] {
+            assert_eq!(key(name).keys, [SingleKey::new(keysym, false)], "{name:?}");
+        }
+        assert_ne!(key("KP_1"), key("1"));
+    }
+
+    #[test]
+    fn numpad_as_digits() {
+        let keymap = keymap("us");
+        let num_lock = state(&keymap, &[], &[xkb::MOD_NAME_NUM], 0);
+        let main = |key: &str| {
+            let keycode = keymap.key_by_name(key).unwrap();
+            numpad_to_main(num_lock.key_get_one_sym(keycode))
+        };
+        assert_eq!(main("KP1"), xkb::Keysym::_1);
+        assert_eq!(main("KPEN"), xkb::Keysym::Return);
+        assert_eq!(main("KPAD"), xkb::Keysym::plus);
+        // Main keys are unchanged
+        assert_eq!(main("AE01"), xkb::Keysym::_1);
+        assert_eq!(main("RTRN"), xkb::Keysym::Return);
+
+        // Without NumLock, the key does not type a digit
+        let keycode = keymap.key_by_name("KP1").unwrap();
+        let keysym = state(&keymap, &[], &[], 0).key_get_one_sym(keycode);
+        assert_eq!(keysym, xkb::Keysym::KP_End);
+        assert_eq!(numpad_to_main(keysym), xkb::Keysym::KP_End);
+    }
+
+    fn compose_state() -> xkb::compose::State {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let table = xkb::compose::Table::new_from_buffer(
+            &context,
+            "<dead_acute> <e> : \"é\" eacute\n<Multi_key> <a> <e> : \"æ\" ae\n",
+            "C",
+            xkb::compose::FORMAT_TEXT_V1,
+            xkb::compose::COMPILE_NO_FLAGS,
+        )
+        .unwrap();
+        xkb::compose::State::new(&table, xkb::compose::STATE_NO_FLAGS)
+    }
+
+    #[test]
+    fn no_symbol() {
+        let keymap = keymap("us");
+        let state = state(&keymap, &[], &[], 0);
+        // A key which exists, but has no symbol in the layout
+        let keycode = (keymap.min_keycode().raw()..=keymap.max_keycode().raw())
+            .map(xkb::Keycode::new)
+            .find(|&keycode| {
+                keymap.key_get_name(keycode).is_some()
+                    && state.key_get_one_sym(keycode).raw() == xkb::keysyms::KEY_NoSymbol
+            })
+            .unwrap();
+        let keysym = state.key_get_one_sym(keycode);
+        assert_eq!(compose(None, keysym, false), None);
+
+        // It does not interrupt a compose sequence
+        let mut compose_state = compose_state();
+        let mut feed = |keysym| compose(Some(&mut compose_state), keysym, false);
+        assert_eq!(feed(xkb::Keysym::dead_acute), None);
+        assert_eq!(feed(keysym), None);
+        assert_eq!(feed(xkb::Keysym::e), Some(xkb::Keysym::eacute));
+    }
+
+    #[test]
+    fn compose_sequences() {
+        let mut compose_state = compose_state();
+        let mut feed = |keysym, bound| compose(Some(&mut compose_state), keysym, bound);
+        assert_eq!(feed(xkb::Keysym::a, false), Some(xkb::Keysym::a));
+        assert_eq!(feed(xkb::Keysym::Multi_key, false), None);
+        assert_eq!(
+            feed(xkb::Keysym::Shift_L, false),
+            Some(xkb::Keysym::Shift_L)
+        );
+        assert_eq!(feed(xkb::Keysym::a, false), None);
+        assert_eq!(feed(xkb::Keysym::e, false), Some(xkb::Keysym::ae));
+        // A cancelled sequence is ignored
+        assert_eq!(feed(xkb::Keysym::dead_acute, false), None);
+        assert_eq!(feed(xkb::Keysym::z, false), None);
+        assert_eq!(feed(xkb::Keysym::e, false), Some(xkb::Keysym::e));
+        // A bound dead key is matched on its own
+        assert_eq!(
+            feed(xkb::Keysym::dead_acute, true),
+            Some(xkb::Keysym::dead_acute)
+        );
+        assert_eq!(feed(xkb::Keysym::e, false), Some(xkb::Keysym::e));
+    }
+
+    #[test]
+    fn bound_key_resets_compose() {
+        let mut compose_state = compose_state();
+        let mut feed = |keysym, bound| compose(Some(&mut compose_state), keysym, bound);
+        assert_eq!(feed(xkb::Keysym::dead_acute, false), None);
+        // A bound key ends the sequence, so the dead key is not combined with a later key
+        assert_eq!(feed(xkb::Keysym::x, true), Some(xkb::Keysym::x));
+        assert_eq!(feed(xkb::Keysym::e, false), Some(xkb::Keysym::e));
+    }
+
+    #[test]
+    fn other_layouts() {
+        let keymap = keymap("us,ru");
+        let keycode = keymap.key_by_name("AC01").unwrap();
+        let bound = |keys: &[&str]| {
+            let keys: Vec<SingleKey> = keys.iter().map(|k| k.parse().unwrap()).collect();
+            move |state: &xkb::State, keysym| keys.contains(&SingleKey::pressed(state, keysym))
+        };
+
+        // With the Russian layout active, the key types `ф`
+        let ru = state(&keymap, &[], &[], 1);
+        assert_eq!(ru.key_get_one_sym(keycode), xkb::Keysym::Cyrillic_ef);
+        let (found, keysym) = find_in_other_layouts(&ru, keycode, bound(&["a"])).unwrap();
+        assert_eq!(keysym, xkb::Keysym::a);
+        assert_eq!(found.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE), 0);
+
+        // Modifiers are kept in the other layout
+        let ru_ctrl = state(&keymap, &[xkb::MOD_NAME_CTRL], &[], 1);
+        let (found, keysym) = find_in_other_layouts(&ru_ctrl, keycode, bound(&["Ctrl+a"])).unwrap();
+        assert_eq!(SingleKey::pressed(&found, keysym), key("Ctrl+a").keys[0]);
+        assert!(find_in_other_layouts(&ru_ctrl, keycode, bound(&["a"])).is_none());
+
+        // The active layout is not tried again
+        let us = state(&keymap, &[], &[], 0);
+        assert!(find_in_other_layouts(&us, keycode, bound(&["a"])).is_none());
+        let (_, keysym) = find_in_other_layouts(&us, keycode, bound(&["Cyrillic_ef"])).unwrap();
+        assert_eq!(keysym, xkb::Keysym::Cyrillic_ef);
+        assert!(find_in_other_layouts(&us, keycode, bound(&["b"])).is_none());
     }
 }
@@ -1,45 +1,239 @@
+mod blur;
 mod color;
 mod config;
+mod dbus;
+mod instance;
 mod key;
 mod menu;
 mod text;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::f64::consts::{FRAC_PI_2, PI, TAU};
-use std::io;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
 use std::os::unix::process::CommandExt;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
 use clap::Parser;
 use pangocairo::cairo;
 
 use wayrs_client::object::ObjectId;
+use wayrs_client::protocol::wl_keyboard::{EnterArgs, LeaveArgs};
 use wayrs_client::protocol::*;
 use wayrs_client::proxy::Proxy;
 use wayrs_client::{global::*, EventCtx};
 use wayrs_client::{Connection, IoMode};
 use wayrs_protocols::wlr_layer_shell_unstable_v1::*;
-use wayrs_utils::keyboard::{Keyboard, KeyboardEvent, KeyboardHandler};
+use wayrs_utils::keyboard::{xkb, Keyboard, KeyboardEvent, KeyboardHandler, RepeatInfo};
 use wayrs_utils::seats::{SeatHandler, Seats};
 use wayrs_utils::shm_alloc::{BufferSpec, ShmAlloc};
 
+/// The axis value of one mouse wheel click.
+const WHEEL_STEP: f64 = 15.0;
+
+/// How long a `capture: true` or `refresh: true` command may run before it is killed and its
+/// output shown, or the descriptions refreshed.
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How much output of a `capture: true` command is kept, the rest is read and discarded.
+const MAX_CAPTURE_BYTES: usize = 64 * 1024;
+
 #[derive(Debug, Parser)]
-#[command(author, version, about)]
+#[command(author, version = env!("WLR_WHICH_KEY_VERSION"), about)]
 struct Args {
     /// The name of the config file to use.
     ///
     /// By default, $XDG_CONFIG_HOME/wlr-which-key/config.yaml or
-    /// ~/.config/wlr-which-key/config.yaml is used.
+    /// ~/.config/wlr-which-key/config.yaml is used, falling back to
+    /// wlr-which-key/config.yaml in $XDG_CONFIG_DIRS (/etc/xdg).
     ///
     /// For example, to use ~/.config/wlr-which-key/print-srceen.yaml, set this to
-    /// "print-srceen". An absolute path can be used too, extension is optional.
+    /// "print-srceen". An absolute path can be used too, extension is optional. Use "-" to read
+    /// the config (YAML or JSON) from stdin.
     config: Option<String>,
+
+    /// Print the available outputs and exit.
+    #[arg(long)]
+    list_outputs: bool,
+
+    /// Print the fonts which are used for the configured fonts and exit.
+    #[arg(long)]
+    list_fonts: bool,
+
+    /// Print the path of the config file which would be loaded and exit.
+    #[arg(long)]
+    print_config_path: bool,
+
+    /// Open the submenu which was shown when the menu was last closed.
+    #[arg(long)]
+    resume: bool,
+
+    /// If this menu is already open, leave it open instead of closing it.
+    #[arg(long)]
+    no_toggle: bool,
+
+    /// Fail instead of printing a warning if some entries can not be selected.
+    #[arg(long)]
+    strict: bool,
+
+    /// Render every page of the menu to PNG files and exit.
+    ///
+    /// The top-level page is written to the given path, other pages to files with the page id
+    /// (as printed by --dump-menu) appended to the name, e.g. "menu-1.png".
+    #[arg(long, value_name = "PATH")]
+    render_to: Option<PathBuf>,
+
+    /// Print every page of the menu in the given format and exit.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    dump_menu: Option<DumpFormat>,
+
+    /// Keep running in the background and show menus requested with the ShowMenu D-Bus method.
+    ///
+    /// Nothing is shown until the first request. The given config is only checked and used for
+    /// settings which can not change later, like blur support.
+    #[arg(long)]
+    dbus: bool,
+}
+
+/// How the menu was closed, used as the exit code. Errors exit with 1 and invalid arguments with
+/// 2, so the other codes stay clear of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitReason {
+    /// A command was run.
+    Selected = 0,
+    /// Closed with a cancel key, by releasing `close_on_release` or by starting the menu again.
+    Cancelled = 10,
+    /// Closed by the compositor.
+    Closed = 11,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum DumpFormat {
+    Json,
+    Yaml,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    let config = config::Config::new(args.config.as_deref().unwrap_or("config"))?;
-    let menu = menu::Menu::new(&config)?;
+    let config_name = args.config.as_deref().unwrap_or("config");
+    if args.print_config_path && config_name == "-" {
+        println!("-");
+        return Ok(());
+    }
+    if args.print_config_path {
+        match config::Config::path(config_name) {
+            Ok(path) => println!("{}", path.display()),
+            // Show every location which was checked
+            Err(_) => {
+                for path in config::Config::candidate_paths(config_name) {
+                    println!("{} (not found)", path.display());
+                }
+            }
+        }
+        return Ok(());
+    }
+    if args.list_outputs {
+        return list_outputs();
+    }
+
+    // Before loading the config, so that closing an open menu is quick
+    let opens_menu =
+        !(args.list_fonts || args.dump_menu.is_some() || args.render_to.is_some() || args.dbus);
+    let instance = if opens_menu {
+        match instance::InstanceGuard::acquire(config_name, !args.no_toggle) {
+            Ok(Some(instance)) => Some(instance),
+            // The menu is already open
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                eprintln!("failed to create the instance socket: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let config = config::Config::new(config_name)?;
+    let mut menu = menu::Menu::new(&config)?;
+    let conflicts = menu.key_conflicts();
+    if args.strict && !conflicts.is_empty() {
+        anyhow::bail!("conflicting keys:\n{}", conflicts.join("\n"));
+    }
+    for conflict in conflicts {
+        eprintln!("warning: {conflict}");
+    }
+    for shadowed in menu.shadowed_builtin_keys(&config) {
+        eprintln!("warning: {shadowed} (set `warn_shadowed_keys: false` if this is intended)");
+    }
+
+    let fonts = [
+        ("font", config.font.0.clone()),
+        ("key_font", config.key_font()),
+        ("desc_font", config.desc_font().clone()),
+    ];
+    if args.list_fonts {
+        for (name, font) in &fonts {
+            match text::resolve_font(font) {
+                Some(resolved) => println!("{name}: {font} -> {resolved}"),
+                None => println!("{name}: {font} -> <no font>"),
+            }
+        }
+        return Ok(());
+    }
+    for (i, (name, font)) in fonts.iter().enumerate() {
+        // Warn only once about fonts which are the same as `font`
+        if fonts[..i].iter().any(|(_, other)| other == font) {
+            continue;
+        }
+        match text::resolve_font(font) {
+            Some(resolved) if !text::family_found(font, &resolved) => eprintln!(
+                "warning: {name} {:?} was not found, {:?} is used instead",
+                font.to_string(),
+                resolved.to_string(),
+            ),
+            Some(_) => (),
+            None => eprintln!("warning: no font could be loaded for {name}"),
+        }
+    }
+
+    if let Some(format) = args.dump_menu {
+        let pages = menu.dump();
+        match format {
+            DumpFormat::Json => println!("{}", serde_json::to_string_pretty(&pages)?),
+            DumpFormat::Yaml => print!("{}", serde_yaml::to_string(&pages)?),
+        }
+        return Ok(());
+    }
+
+    let last_page_file = config::last_page_file(config_name);
+    if args.resume {
+        if let Some(path) = &last_page_file {
+            if let Ok(last_page) = std::fs::read_to_string(path) {
+                menu.navigate_to(&last_page.lines().map(String::from).collect::<Vec<_>>());
+            }
+        }
+    }
+    let background_image = config
+        .background_image
+        .as_deref()
+        .map(load_image)
+        .transpose()?;
+
+    if let Some(path) = &args.render_to {
+        return render_to_png(path, &config, &mut menu, background_image.as_ref());
+    }
+
+    let service = match args.dbus {
+        true => Some(dbus::Service::start()?),
+        false => None,
+    };
 
     let mut conn = Connection::connect()?;
     conn.blocking_roundtrip()?;
@@ -48,124 +242,487 @@ fn main() -> anyhow::Result<()> {
     let wl_compositor: WlCompositor = conn.bind_singleton(4..=6)?;
     let wlr_layer_shell: ZwlrLayerShellV1 = conn.bind_singleton(2)?;
 
+    let wl_data_device_manager: Option<WlDataDeviceManager> = conn.bind_singleton(1..=3).ok();
+
+    let blur_manager: Option<blur::OrgKdeKwinBlurManager> = match config.blur || args.dbus {
+        true => conn.bind_singleton(1).ok(),
+        false => None,
+    };
+
     let seats = Seats::bind(&mut conn);
     let shm_alloc = ShmAlloc::bind(&mut conn)?;
 
-    let width = (menu.width() + (config.padding() + config.border_width) * 2.0) as u32;
-    let height = (menu.height() + (config.padding() + config.border_width) * 2.0) as u32;
+    let (width, height) = surface_size(&menu, &config);
 
     let wl_surface = wl_compositor.create_surface_with_cb(&mut conn, wl_surface_cb);
-
-    let layer_surface = wlr_layer_shell.get_layer_surface_with_cb(
-        &mut conn,
-        wl_surface,
-        None,
-        zwlr_layer_shell_v1::Layer::Overlay,
-        wayrs_client::cstr!("wlr_which_key").into(),
-        layer_surface_cb,
-    );
-    layer_surface.set_anchor(&mut conn, config.anchor.into());
-    layer_surface.set_size(&mut conn, width, height);
-    layer_surface.set_margin(
-        &mut conn,
-        config.margin_top,
-        config.margin_right,
-        config.margin_bottom,
-        config.margin_left,
-    );
-    layer_surface.set_keyboard_interactivity(
-        &mut conn,
-        zwlr_layer_surface_v1::KeyboardInteractivity::Exclusive,
-    );
-    wl_surface.commit(&mut conn);
+    let blur = blur_manager
+        .filter(|_| config.blur && !args.dbus)
+        .map(|manager| manager.create(&mut conn, wl_surface));
 
     let mut state = State {
         shm_alloc,
         seats,
         keyboards: Vec::new(),
+        compose: key::compose_state(),
+        pointers: Vec::new(),
         outputs: Vec::new(),
 
+        wlr_layer_shell,
+        wl_data_device_manager,
+        wl_compositor,
+        blur_manager,
+        blur,
+        blur_rect: None,
+        input_rect: None,
+        data_devices: Vec::new(),
+        clipboard: None,
         wl_surface,
-        layer_surface,
+        layer_surface: None,
+        placed_on: None,
+        waiting_for_output: false,
+        locating_cursor: false,
+        placed_at_cursor: false,
+        margins_clamped: false,
+        visible: config.show_delay == 0,
+        show_at: Instant::now() + Duration::from_millis(config.show_delay),
+        shown_at: None,
+        transition: None,
+        repeat: None,
+        capture: None,
+        refresh: None,
+        activations: HashMap::new(),
+        scroll: 0.0,
+        wheel: 0.0,
+        lone_modifier: None,
+        sticky_held: false,
+        menu_cycle: iter::once(config_name)
+            .chain(config.cycle_menus.iter().map(String::as_str))
+            .map(|name| (name.to_owned(), None))
+            .collect(),
+        menu_index: 0,
+        last_activity: Instant::now(),
+        close_on_click: false,
+        had_keyboard_focus: false,
         visible_on_outputs: HashSet::new(),
         surface_scale: 1,
-        exit: false,
+        exit: None,
         configured: false,
         width,
         height,
         throttle_cb: None,
         throttled: false,
+        dirty: true,
+        drawn_scale: 0,
 
+        background_image,
         menu,
         config,
     };
 
-    while !state.exit {
+    // Bind the outputs and receive their names before choosing where to place the menu
+    conn.dispatch_events(&mut state);
+    conn.blocking_roundtrip()?;
+    conn.dispatch_events(&mut state);
+
+    if state.keyboards.is_empty() {
+        eprintln!("warning: no keyboard found, click the menu to close it");
+        state.close_on_click = true;
+    }
+
+    if service.is_none() {
+        state.show(&mut conn);
+    }
+
+    let instance_fd = instance.as_ref().map_or(-1, AsRawFd::as_raw_fd);
+    let service_fd = service.as_ref().map_or(-1, AsRawFd::as_raw_fd);
+
+    loop {
+        if state.exit.is_some() {
+            if service.is_none() {
+                break;
+            }
+            state.hide(&mut conn);
+        }
+
         conn.flush(IoMode::Blocking)?;
+
+        let idle_deadline = state
+            .config
+            .idle_timeout_ms
+            .filter(|_| state.layer_surface.is_some())
+            .map(|timeout| state.last_activity + Duration::from_millis(timeout));
+        let wake_at = [
+            (!state.visible).then_some(state.show_at),
+            state.repeat.as_ref().map(|repeat| repeat.next),
+            state.capture.as_ref().map(|capture| capture.deadline),
+            state.refresh.as_ref().map(|refresh| refresh.deadline),
+            idle_deadline,
+        ];
+        let timeout = wake_at
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|at| at.saturating_duration_since(Instant::now()));
+        let capture_fd = state
+            .capture
+            .as_ref()
+            .map_or(-1, |capture| capture.stdout.as_raw_fd());
+        let refresh_fd = state
+            .refresh
+            .as_ref()
+            .map_or(-1, |refresh| refresh.stdout.as_raw_fd());
+        let [wayland_ready, instance_ready, capture_ready, refresh_ready, service_ready] =
+            poll_readable(
+                [
+                    conn.as_raw_fd(),
+                    instance_fd,
+                    capture_fd,
+                    refresh_fd,
+                    service_fd,
+                ],
+                timeout,
+            )?;
+
+        if instance_ready
+            && instance
+                .as_ref()
+                .is_some_and(instance::InstanceGuard::close_requested)
+        {
+            state.exit = Some(ExitReason::Cancelled);
+            continue;
+        }
+
+        if idle_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            state.exit = Some(ExitReason::Cancelled);
+            continue;
+        }
+
+        if service_ready {
+            for request in service.as_ref().unwrap().take_requests() {
+                state.show_requested(&mut conn, request);
+            }
+        }
+
+        if !state.visible && Instant::now() >= state.show_at {
+            state.visible = true;
+            state.redraw(&mut conn);
+        }
+        state.repeat_key();
+        if capture_ready
+            || state
+                .capture
+                .as_ref()
+                .is_some_and(|capture| Instant::now() >= capture.deadline)
+        {
+            state.poll_capture(&mut conn);
+        }
+        if refresh_ready
+            || state
+                .refresh
+                .as_ref()
+                .is_some_and(|refresh| Instant::now() >= refresh.deadline)
+        {
+            state.poll_refresh(&mut conn);
+        }
+
+        if !wayland_ready {
+            continue;
+        }
+
         conn.recv_events(IoMode::Blocking)?;
         conn.dispatch_events(&mut state);
     }
 
-    Ok(())
+    // Saved on every exit, so that `--resume` never restores a page of an older session
+    if state.menu_index == 0 {
+        if let Some(path) = &last_page_file {
+            if let Err(e) = save_last_page(path, &state.menu.page_path()) {
+                eprintln!("failed to save the last page to {}: {e}", path.display());
+            }
+        }
+    }
+
+    // `process::exit` does not run destructors
+    drop(instance);
+    let exit = state.exit.unwrap_or(ExitReason::Cancelled);
+    std::process::exit(exit as i32);
 }
 
 struct State {
     shm_alloc: ShmAlloc,
     seats: Seats,
     keyboards: Vec<Keyboard>,
+    compose: Option<xkb::compose::State>,
+    pointers: Vec<(WlSeat, WlPointer)>,
     outputs: Vec<Output>,
 
+    wlr_layer_shell: ZwlrLayerShellV1,
+    wl_data_device_manager: Option<WlDataDeviceManager>,
+    wl_compositor: WlCompositor,
+    /// Bound if blur is enabled (or may be enabled later in `--dbus` mode) and supported by the
+    /// compositor.
+    blur_manager: Option<blur::OrgKdeKwinBlurManager>,
+    /// Blurs the area behind the window, if enabled and supported by the compositor.
+    blur: Option<blur::OrgKdeKwinBlur>,
+    /// The window area which is currently blurred.
+    blur_rect: Option<(i32, i32, i32, i32)>,
+    /// The window area which receives pointer events, the whole surface if `None`.
+    input_rect: Option<(i32, i32, i32, i32)>,
+    data_devices: Vec<(WlSeat, WlDataDevice)>,
+    /// The data source of the copied page and its text.
+    clipboard: Option<(WlDataSource, String)>,
     wl_surface: WlSurface,
-    layer_surface: ZwlrLayerSurfaceV1,
+    /// Created once the configured output (if any) is available.
+    layer_surface: Option<ZwlrLayerSurfaceV1>,
+    /// The output whose placement settings are currently applied.
+    placed_on: Option<String>,
+    /// Whether the configured output was missing at startup.
+    waiting_for_output: bool,
+    /// Whether the surface covers the whole output until the pointer enters it, to place the
+    /// window at the cursor.
+    locating_cursor: bool,
+    placed_at_cursor: bool,
+    /// Whether the margins are reduced by `clamp_to_output`, to warn only when this starts.
+    margins_clamped: bool,
+    /// Whether the menu is shown, false until `show_delay` elapses.
+    visible: bool,
+    /// When `show_delay` elapses.
+    show_at: Instant,
+    /// When the menu was first drawn while visible, for the fade-in animation.
+    shown_at: Option<Instant>,
+    /// The page being switched away from and when the switch started, while the page
+    /// transition animation runs.
+    transition: Option<(usize, Instant)>,
+    /// The held key whose command is run repeatedly.
+    repeat: Option<KeyRepeat>,
+    /// The running `capture: true` command, whose output is shown once it finishes.
+    capture: Option<Capture>,
+    /// The running `refresh: true` command, the descriptions are updated once it finishes.
+    refresh: Option<Capture>,
+    /// How many times the commands of `keep_open: { max: N }` entries ran, by page and command.
+    activations: HashMap<(usize, Vec<String>), u32>,
+    /// How far the page is scrolled down, if it is taller than the window.
+    scroll: f64,
+    /// The modifier of `modifier_actions` which was pressed last, until another key is pressed.
+    lone_modifier: Option<key::Modifier>,
+    /// Whether the sticky modifier is held, for entries selected without pressing their key.
+    sticky_held: bool,
+    /// Mouse wheel movement not yet turned into `scroll_up` or `scroll_down` selections.
+    wheel: f64,
+    /// The configs of the menus switched between with Tab, and the menus which were already
+    /// built. The first one is the config the program was started with.
+    menu_cycle: Vec<(String, Option<menu::Menu>)>,
+    /// The index of the shown menu in `menu_cycle`.
+    menu_index: usize,
+    /// When the last key was pressed, or the menu was opened, for `idle_timeout_ms`.
+    last_activity: Instant,
+    /// Whether clicking closes the menu, because there was no keyboard to close it with.
+    close_on_click: bool,
+    /// Whether the menu has received keyboard focus since it was opened.
+    had_keyboard_focus: bool,
     visible_on_outputs: HashSet<ObjectId>,
     surface_scale: u32,
-    exit: bool,
+    /// Why the menu is closing, set once it should close.
+    exit: Option<ExitReason>,
     configured: bool,
     width: u32,
     height: u32,
     throttle_cb: Option<WlCallback>,
     throttled: bool,
+    /// Whether the content of the window changed since it was last drawn. Drawing is skipped
+    /// otherwise, unless the buffer scale changed.
+    dirty: bool,
+    /// The buffer scale of the last drawn frame.
+    drawn_scale: u32,
 
+    background_image: Option<cairo::ImageSurface>,
     menu: menu::Menu,
     config: config::Config,
 }
 
+struct KeyRepeat {
+    keycode: xkb::Keycode,
+    cmd: Vec<String>,
+    env: Vec<(&'static str, String)>,
+    next: Instant,
+    interval: Duration,
+}
+
+/// A `capture: true` or `refresh: true` command whose output is read without blocking the event
+/// loop.
+struct Capture {
+    child: Child,
+    stdout: ChildStdout,
+    output: Vec<u8>,
+    /// When the command is killed if it has not finished.
+    deadline: Instant,
+}
+
+impl Capture {
+    fn spawn(mut proc: Command) -> io::Result<Self> {
+        let mut child = proc.stdin(Stdio::null()).stdout(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        // Safety: the fd is open for as long as `stdout` lives
+        unsafe {
+            let fd = stdout.as_raw_fd();
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+        Ok(Self {
+            child,
+            stdout,
+            output: Vec::new(),
+            deadline: Instant::now() + CAPTURE_TIMEOUT,
+        })
+    }
+
+    /// Read the output which is available now. Returns whether the command closed its output.
+    fn read(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stdout.read(&mut buf) {
+                Ok(0) => return Ok(true),
+                Ok(n) => {
+                    let keep = n.min(MAX_CAPTURE_BYTES.saturating_sub(self.output.len()));
+                    self.output.extend_from_slice(&buf[..keep]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for Capture {
+    /// Kill the command if it is still running, and reap it.
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
 struct Output {
     wl: WlOutput,
     reg_name: u32,
+    name: Option<String>,
+    description: Option<String>,
+    make: String,
+    model: String,
+    /// Physical size in millimeters.
+    physical_size: (i32, i32),
+    /// Size of the current mode in pixels.
+    mode_size: Option<(i32, i32)>,
     scale: u32,
 }
 
+impl Output {
+    fn new(wl: WlOutput, reg_name: u32) -> Self {
+        Self {
+            wl,
+            reg_name,
+            name: None,
+            description: None,
+            make: String::new(),
+            model: String::new(),
+            physical_size: (0, 0),
+            mode_size: None,
+            scale: 1,
+        }
+    }
+
+    /// Apply `event` to the output. Returns whether its scale or current mode changed.
+    fn update(&mut self, event: wl_output::Event) -> bool {
+        match event {
+            wl_output::Event::Scale(scale) => {
+                let scale: u32 = scale.try_into().unwrap();
+                let changed = self.scale != scale;
+                self.scale = scale;
+                changed
+            }
+            wl_output::Event::Name(name) => {
+                self.name = Some(name.to_string_lossy().into_owned());
+                false
+            }
+            wl_output::Event::Description(description) => {
+                self.description = Some(description.to_string_lossy().into_owned());
+                false
+            }
+            wl_output::Event::Geometry(args) => {
+                self.make = args.make.to_string_lossy().into_owned();
+                self.model = args.model.to_string_lossy().into_owned();
+                self.physical_size = (args.physical_width, args.physical_height);
+                false
+            }
+            wl_output::Event::Mode(args) if args.flags.contains(wl_output::Mode::Current) => {
+                let changed = self
+                    .mode_size
+                    .is_some_and(|size| size != (args.width, args.height));
+                self.mode_size = Some((args.width, args.height));
+                changed
+            }
+            _ => false,
+        }
+    }
+
+    /// The size of the current mode in surface-local coordinates.
+    fn logical_size(&self) -> Option<(i32, i32)> {
+        let scale = self.scale as i32;
+        self.mode_size
+            .map(|(width, height)| (width / scale, height / scale))
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name.as_deref().unwrap_or("<unnamed>"))?;
+        if let Some(description) = &self.description {
+            write!(f, " ({description})")?;
+        } else {
+            write!(f, " ({} {})", self.make, self.model)?;
+        }
+        if let Some((width, height)) = self.mode_size {
+            write!(f, " {width}x{height}")?;
+        }
+        let (physical_w, physical_h) = self.physical_size;
+        write!(f, " {physical_w}x{physical_h}mm scale {}", self.scale)
+    }
+}
+
 impl State {
+    /// Draw the window again, because its content changed.
+    fn redraw(&mut self, conn: &mut Connection<Self>) {
+        self.dirty = true;
+        self.draw(conn);
+    }
+
+    /// Draw the window if it changed since the last frame. Only one frame is drawn per frame
+    /// callback, later calls are deferred until the callback arrives.
     fn draw(&mut self, conn: &mut Connection<Self>) {
-        if !self.configured {
+        let scale = self.buffer_scale();
+        if !self.configured || (!self.dirty && scale == self.drawn_scale) {
             return;
         }
 
+        // `dirty` stays set while throttled, so the deferred draw is not skipped
         if self.throttle_cb.is_some() {
             self.throttled = true;
             return;
         }
 
         self.throttle_cb = Some(self.wl_surface.frame_with_cb(conn, |ctx| {
-            assert_eq!(ctx.state.throttle_cb, Some(ctx.proxy));
+            // A callback of a menu hidden in `--dbus` mode may arrive after the next one is shown
+            if ctx.state.throttle_cb != Some(ctx.proxy) {
+                return;
+            }
             ctx.state.throttle_cb = None;
             if ctx.state.throttled {
                 ctx.state.throttled = false;
                 ctx.state.draw(ctx.conn);
             }
         }));
-
-        let scale = if self.wl_surface.version() >= 6 {
-            self.surface_scale
-        } else {
-            self.outputs
-                .iter()
-                .filter(|o| self.visible_on_outputs.contains(&o.wl.id()))
-                .map(|o| o.scale)
-                .max()
-                .unwrap_or(1)
-        };
+        self.dirty = false;
+        self.drawn_scale = scale;
 
         let width_f = self.width as f64;
         let height_f = self.height as f64;
@@ -205,47 +762,41 @@ impl State {
         cairo_ctx.paint().unwrap();
         cairo_ctx.restore().unwrap();
 
-        cairo_ctx.new_sub_path();
-        let half_border = self.config.border_width * 0.5;
-        let r = self.config.corner_r;
-        cairo_ctx.arc(r + half_border, r + half_border, r, PI, 3.0 * FRAC_PI_2);
-        cairo_ctx.arc(
-            width_f - r - half_border,
-            r + half_border,
-            r,
-            3.0 * FRAC_PI_2,
-            TAU,
-        );
-        cairo_ctx.arc(
-            width_f - r - half_border,
-            height_f - r - half_border,
-            r,
-            0.0,
-            FRAC_PI_2,
-        );
-        cairo_ctx.arc(
-            r + half_border,
-            height_f - r - half_border,
-            r,
-            FRAC_PI_2,
-            PI,
-        );
-        cairo_ctx.close_path();
-        self.config.background.apply(&cairo_ctx);
-        cairo_ctx.fill_preserve().unwrap();
-        self.config.border.apply(&cairo_ctx);
-        cairo_ctx.set_line_width(self.config.border_width);
-        cairo_ctx.stroke().unwrap();
-
-        // draw our menu
-        self.menu
-            .render(
-                &self.config,
-                &cairo_ctx,
-                self.config.padding() + self.config.border_width,
-                self.config.padding() + self.config.border_width,
-            )
-            .unwrap();
+        // Until the show delay elapses the surface is mapped, but fully transparent
+        let fade = match self.visible {
+            true => self.fade_progress(),
+            false => 1.0,
+        };
+        let transition = self.transition_progress();
+        if fade < 1.0 || transition.is_some() {
+            // Draw the next step of the animation on the next frame
+            self.dirty = true;
+            self.throttled = true;
+        }
+        if self.visible && self.locating_cursor {
+            // Show the menu centered while waiting for the pointer
+            let (width, height) = self.surface_size();
+            let (width, height) = (width as f64, height as f64);
+            let (x, y) = (
+                ((width_f - width) * 0.5).round(),
+                ((height_f - height) * 0.5).round(),
+            );
+            cairo_ctx.translate(x, y);
+            self.render(&cairo_ctx, width, height, fade, transition);
+            self.update_blur(conn, x, y, width, height);
+            // The pointer must be able to enter the surface anywhere
+            self.update_input_region(conn, None);
+        } else if self.visible {
+            self.render(&cairo_ctx, width_f, height_f, fade, transition);
+            self.update_blur(conn, 0.0, 0.0, width_f, height_f);
+            let rect = self.window_rect(0.0, 0.0, width_f, height_f);
+            self.update_input_region(conn, Some(rect));
+        } else {
+            // Let clicks pass through the transparent surface until it is shown, unless the
+            // pointer has yet to enter it to place the window
+            let rect = (!self.locating_cursor).then_some((0, 0, 0, 0));
+            self.update_input_region(conn, rect);
+        }
 
         // Damage the entire window
         self.wl_surface.damage_buffer(
@@ -262,161 +813,1649 @@ impl State {
         self.wl_surface.commit(conn);
     }
 
-    fn bind_output(&mut self, conn: &mut Connection<Self>, global: &Global) {
-        let wl: WlOutput = global.bind_with_cb(conn, 1..=4, wl_output_cb).unwrap();
-        self.outputs.push(Output {
-            wl,
-            reg_name: global.name,
-            scale: 1,
-        });
+    /// The scale of the buffers, the preferred one of the surface or the highest scale of the
+    /// outputs it is shown on.
+    fn buffer_scale(&self) -> u32 {
+        if self.wl_surface.version() >= 6 {
+            self.surface_scale
+        } else {
+            self.outputs
+                .iter()
+                .filter(|o| self.visible_on_outputs.contains(&o.wl.id()))
+                .map(|o| o.scale)
+                .max()
+                .unwrap_or(1)
+        }
     }
-}
 
-impl SeatHandler for State {
-    fn get_seats(&mut self) -> &mut Seats {
-        &mut self.seats
+    /// Render the window, including the shadow, to a `width`x`height` area at the origin.
+    fn render(
+        &self,
+        cairo_ctx: &cairo::Context,
+        width: f64,
+        height: f64,
+        alpha: f64,
+        transition: Option<(usize, f64)>,
+    ) {
+        render_window(
+            cairo_ctx,
+            width,
+            height,
+            alpha,
+            transition,
+            self.scroll,
+            &self.config,
+            &self.menu,
+            self.background_image.as_ref(),
+        );
     }
 
-    fn keyboard_added(&mut self, conn: &mut Connection<Self>, seat: WlSeat) {
-        self.keyboards.push(Keyboard::new(conn, seat));
+    /// The page being switched away from and how far the transition is, from `0.0` to `1.0`,
+    /// or `None` once it is over.
+    fn transition_progress(&mut self) -> Option<(usize, f64)> {
+        let (from, start) = self.transition?;
+        let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+        let progress = elapsed / self.config.transition_ms as f64;
+        if progress >= 1.0 || self.menu.has_output() {
+            self.transition = None;
+            return None;
+        }
+        Some((from, progress))
     }
 
-    fn keyboard_removed(&mut self, conn: &mut Connection<Self>, seat: WlSeat) {
-        let i = self
-            .keyboards
-            .iter()
-            .position(|k| k.seat() == seat)
-            .unwrap();
-        let keyboard = self.keyboards.swap_remove(i);
-        keyboard.destroy(conn);
+    /// Run the command of the held key again if the repeat interval has passed.
+    fn repeat_key(&mut self) {
+        let Some(repeat) = &mut self.repeat else {
+            return;
+        };
+        let now = Instant::now();
+        if repeat.next > now {
+            return;
+        }
+        repeat.next = now + repeat.interval;
+        if let Err(e) = spawn_detached(shell_command(&repeat.cmd, &repeat.env)) {
+            eprintln!("failed to run the repeated command: {e}");
+            self.repeat = None;
+        }
     }
-}
 
-impl KeyboardHandler for State {
-    fn get_keyboard(&mut self, wl_keyboard: WlKeyboard) -> &mut Keyboard {
-        self.keyboards
-            .iter_mut()
-            .find(|k| k.wl_keyboard() == wl_keyboard)
-            .unwrap()
+    /// Read the output of the running `capture: true` command, and show it once the command
+    /// finishes or times out.
+    fn poll_capture(&mut self, conn: &mut Connection<Self>) {
+        let Some(capture) = &mut self.capture else {
+            return;
+        };
+        let note = match capture.read() {
+            Ok(true) => None,
+            Ok(false) if Instant::now() < capture.deadline => return,
+            Ok(false) => Some(format!("(timed out after {}s)", CAPTURE_TIMEOUT.as_secs())),
+            Err(e) => Some(format!("(failed to read the output: {e})")),
+        };
+        // Killed when dropped, if it keeps running after closing its output
+        let capture = self.capture.take().unwrap();
+        let mut output = String::from_utf8_lossy(&capture.output).into_owned();
+        if let Some(note) = note {
+            output.truncate(output.trim_end().len());
+            output.push('\n');
+            output.push_str(&note);
+        }
+        self.menu.show_output(&output, &self.config);
+        self.resize(conn);
     }
 
-    fn key_presed(&mut self, conn: &mut Connection<Self>, event: KeyboardEvent) {
-        if let Some(action) = self.menu.get_action(&event.xkb_state, event.keysym) {
-            match action {
-                menu::Action::Quit => {
-                    self.exit = true;
-                    conn.break_dispatch_loop();
-                }
-                menu::Action::Exec { cmd, keep_open } => {
-                    let mut proc = Command::new("sh");
-                    proc.args(["-c", &cmd]);
-                    proc.stdin(Stdio::null());
-                    proc.stdout(Stdio::null());
-                    // Safety: libc::daemon() is async-signal-safe
-                    unsafe {
-                        proc.pre_exec(|| match libc::daemon(1, 0) {
-                            -1 => Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                "Failed to detach new process",
-                            )),
-                            _ => Ok(()),
-                        });
-                    }
-                    proc.spawn().unwrap().wait().unwrap();
-                    if !keep_open {
-                        self.exit = true;
+    /// Check whether the running `refresh: true` command finished, and update the descriptions
+    /// of the current page if so. Its output is discarded.
+    fn poll_refresh(&mut self, conn: &mut Connection<Self>) {
+        let Some(refresh) = &mut self.refresh else {
+            return;
+        };
+        match refresh.read() {
+            Ok(false) if Instant::now() < refresh.deadline => return,
+            Ok(_) => (),
+            Err(e) => eprintln!("failed to wait for the refreshing command: {e}"),
+        }
+        // Killed if it takes too long
+        self.refresh = None;
+        self.menu.refresh_page(&self.config);
+        self.resize(conn);
+    }
+
+    /// Show the menu `step` places after the current one in `menu_cycle`. The menus are built
+    /// with the theme of the current config.
+    fn cycle_menu(&mut self, conn: &mut Connection<Self>, step: usize) {
+        let next = (self.menu_index + step) % self.menu_cycle.len();
+        let menu = match self.menu_cycle[next].1.take() {
+            Some(menu) => menu,
+            None => {
+                let built = config::Config::new(&self.menu_cycle[next].0).and_then(|other| {
+                    self.config.menu = other.menu;
+                    menu::Menu::new(&self.config)
+                });
+                match built {
+                    Ok(menu) => menu,
+                    Err(e) => {
+                        eprintln!("failed to load menu {:?}: {e:#}", self.menu_cycle[next].0);
+                        return;
                     }
                 }
-                menu::Action::Submenu(page) => {
-                    self.menu.set_page(page);
-
-                    self.width = (self.menu.width()
-                        + (self.config.padding() + self.config.border_width) * 2.0)
-                        as u32;
-                    self.height = (self.menu.height()
-                        + (self.config.padding() + self.config.border_width) * 2.0)
-                        as u32;
-
-                    self.layer_surface.set_size(conn, self.width, self.height);
-                    self.wl_surface.commit(conn);
-                }
             }
+        };
+        let mut previous = mem::replace(&mut self.menu, menu);
+        previous.set_page(0);
+        self.menu_cycle[self.menu_index].1 = Some(previous);
+        self.menu_index = next;
+        self.menu.set_page(0);
+        self.transition = None;
+        // Page ids are per menu
+        self.activations.clear();
+        self.relayout();
+        self.resize(conn);
+    }
+
+    /// Switch to `page`, starting the transition animation if enabled.
+    fn open_page(&mut self, conn: &mut Connection<Self>, page: usize) {
+        let from = self.menu.current_page();
+        if self.config.transition != config::Transition::None
+            && self.config.transition_ms > 0
+            && page != from
+            && !self.menu.has_output()
+        {
+            self.transition = Some((from, Instant::now()));
         }
+        self.menu.set_page(page);
+        self.resize(conn);
     }
 
-    fn key_released(&mut self, _: &mut Connection<Self>, _: KeyboardEvent) {}
-}
+    /// Blur the area behind the window, which is drawn to the `width`x`height` area at `(x, y)`
+    /// including the shadow.
+    fn update_blur(
+        &mut self,
+        conn: &mut Connection<Self>,
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    ) {
+        let Some(blur) = self.blur else {
+            return;
+        };
+        let rect = self.window_rect(x, y, width, height);
+        if self.blur_rect == Some(rect) {
+            return;
+        }
+        self.blur_rect = Some(rect);
+        let region = rounded_region(conn, self.wl_compositor, rect, self.config.corner_r);
+        blur.set_region(conn, Some(region));
+        blur.commit(conn);
+        region.destroy(conn);
+    }
 
-fn wl_registry_cb(conn: &mut Connection<State>, state: &mut State, event: &wl_registry::Event) {
-    match event {
-        wl_registry::Event::Global(g) if g.is::<WlOutput>() => state.bind_output(conn, g),
-        wl_registry::Event::GlobalRemove(name) => {
-            if let Some(output_i) = state.outputs.iter().position(|o| o.reg_name == *name) {
-                let output = state.outputs.swap_remove(output_i);
-                state.visible_on_outputs.remove(&output.wl.id());
-                if output.wl.version() >= 3 {
-                    output.wl.release(conn);
-                }
+    /// Limit pointer events to the window with its rounded corners, so clicks on the shadow and
+    /// the transparent corners reach the surfaces below. `None` covers the whole surface.
+    fn update_input_region(
+        &mut self,
+        conn: &mut Connection<Self>,
+        rect: Option<(i32, i32, i32, i32)>,
+    ) {
+        if self.input_rect == rect {
+            return;
+        }
+        self.input_rect = rect;
+        match rect {
+            Some(rect) => {
+                let region = rounded_region(conn, self.wl_compositor, rect, self.config.corner_r);
+                self.wl_surface.set_input_region(conn, Some(region));
+                region.destroy(conn);
             }
+            None => self.wl_surface.set_input_region(conn, None),
         }
-        _ => (),
     }
-}
 
-fn wl_output_cb(ctx: EventCtx<State, WlOutput>) {
-    if let wl_output::Event::Scale(scale) = ctx.event {
-        let output = ctx
-            .state
-            .outputs
-            .iter_mut()
-            .find(|o| o.wl == ctx.proxy)
-            .unwrap();
-        let scale: u32 = scale.try_into().unwrap();
-        if output.scale != scale {
-            output.scale = scale;
-            ctx.state.draw(ctx.conn);
+    /// The window without the shadow, when it is drawn to the `width`x`height` area at `(x, y)`.
+    fn window_rect(&self, x: f64, y: f64, width: f64, height: f64) -> (i32, i32, i32, i32) {
+        let shadow_margin = self.config.shadow_margin();
+        (
+            (x + shadow_margin) as i32,
+            (y + shadow_margin) as i32,
+            (width - shadow_margin * 2.0) as i32,
+            (height - shadow_margin * 2.0) as i32,
+        )
+    }
+
+    /// How far the fade-in animation is, from `0.0` to `1.0`. Starts the animation on the first
+    /// call.
+    fn fade_progress(&mut self) -> f64 {
+        let shown_at = *self.shown_at.get_or_insert_with(Instant::now);
+        if self.config.fade_in_ms == 0 {
+            return 1.0;
         }
+        let elapsed = shown_at.elapsed().as_secs_f64() * 1000.0;
+        (elapsed / self.config.fade_in_ms as f64).min(1.0)
     }
-}
 
-fn wl_surface_cb(ctx: EventCtx<State, WlSurface>) {
-    assert_eq!(ctx.proxy, ctx.state.wl_surface);
-    match ctx.event {
-        wl_surface::Event::Enter(output) => {
-            ctx.state.visible_on_outputs.insert(output);
-            ctx.state.draw(ctx.conn);
+    /// Create the layer surface, or wait for the configured output if it is not present.
+    fn show(&mut self, conn: &mut Connection<Self>) {
+        self.create_layer_surface(conn);
+        if self.layer_surface.is_none() {
+            self.waiting_for_output = true;
+            eprintln!(
+                "output {:?} is not present, waiting for it to appear",
+                self.config.output.as_deref().unwrap_or_default(),
+            );
+            for output in &self.outputs {
+                eprintln!("available output: {output}");
+            }
         }
-        wl_surface::Event::Leave(output) => {
-            ctx.state.visible_on_outputs.remove(&output);
+    }
+
+    /// Unmap the closed menu in `--dbus` mode, and forget the state of its surface.
+    fn hide(&mut self, conn: &mut Connection<Self>) {
+        if let Some(layer_surface) = self.layer_surface.take() {
+            self.wl_surface.attach(conn, None, 0, 0);
+            self.wl_surface.commit(conn);
+            layer_surface.destroy(conn);
         }
-        wl_surface::Event::PreferredBufferScale(scale) => {
-            assert!(scale >= 1);
-            let scale = scale as u32;
-            if ctx.state.surface_scale != scale {
-                ctx.state.surface_scale = scale;
-                ctx.state.draw(ctx.conn);
-            }
+        if let (Some(manager), Some(blur)) = (self.blur_manager, self.blur.take()) {
+            manager.unset(conn, self.wl_surface);
+            blur.release(conn);
         }
-        _ => (),
+        self.capture = None;
+        self.refresh = None;
+        self.exit = None;
+        self.waiting_for_output = false;
+        self.configured = false;
+        self.throttle_cb = None;
+        self.throttled = false;
+        self.blur_rect = None;
+        self.input_rect = None;
+        self.repeat = None;
+        self.lone_modifier = None;
+        self.sticky_held = false;
+        self.had_keyboard_focus = false;
     }
-}
 
-fn layer_surface_cb(ctx: EventCtx<State, ZwlrLayerSurfaceV1>) {
-    assert_eq!(ctx.proxy, ctx.state.layer_surface);
-    match ctx.event {
-        zwlr_layer_surface_v1::Event::Configure(args) => {
-            if args.width != 0 {
+    /// Show the menu requested with the `ShowMenu` D-Bus method, replacing the shown one.
+    fn show_requested(&mut self, conn: &mut Connection<Self>, request: dbus::ShowMenu) {
+        let name = match request.config.as_str() {
+            "" => "config",
+            name => name,
+        };
+        let loaded = config::Config::new(name).and_then(|config| {
+            let menu = menu::Menu::new(&config)?;
+            let background_image = config
+                .background_image
+                .as_deref()
+                .map(load_image)
+                .transpose()?;
+            Ok((config, menu, background_image))
+        });
+        let (config, mut menu, background_image) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprintln!("failed to load menu {name:?}: {e:#}");
+                return;
+            }
+        };
+        menu.navigate_to(&request.path);
+
+        self.hide(conn);
+        self.menu_cycle = iter::once(name)
+            .chain(config.cycle_menus.iter().map(String::as_str))
+            .map(|name| (name.to_owned(), None))
+            .collect();
+        self.menu_index = 0;
+        self.visible = config.show_delay == 0;
+        self.show_at = Instant::now() + Duration::from_millis(config.show_delay);
+        self.shown_at = None;
+        self.transition = None;
+        self.activations.clear();
+        self.scroll = 0.0;
+        self.wheel = 0.0;
+        self.last_activity = Instant::now();
+        self.locating_cursor = false;
+        self.placed_at_cursor = false;
+        self.margins_clamped = false;
+        self.dirty = true;
+        if config.blur {
+            self.blur = self
+                .blur_manager
+                .map(|manager| manager.create(conn, self.wl_surface));
+        }
+        self.background_image = background_image;
+        self.menu = menu;
+        self.config = config;
+        self.show(conn);
+    }
+
+    /// Create the layer surface, unless it already exists or the configured output is not
+    /// available yet.
+    fn create_layer_surface(&mut self, conn: &mut Connection<Self>) {
+        if self.layer_surface.is_some() {
+            return;
+        }
+
+        let output = match &self.config.output {
+            None => None,
+            Some(name) => match self.outputs.iter().find(|o| o.name.as_ref() == Some(name)) {
+                Some(output) => Some(output.wl),
+                None => return,
+            },
+        };
+
+        let layer_surface = self.wlr_layer_shell.get_layer_surface_with_cb(
+            conn,
+            self.wl_surface,
+            output,
+            self.config.layer.into(),
+            wayrs_client::cstr!("wlr_which_key").into(),
+            layer_surface_cb,
+        );
+        self.relayout();
+        (self.width, self.height) = self.surface_size();
+        layer_surface.set_size(conn, self.width, self.height);
+        layer_surface.set_keyboard_interactivity(
+            conn,
+            zwlr_layer_surface_v1::KeyboardInteractivity::Exclusive,
+        );
+        self.layer_surface = Some(layer_surface);
+        self.placed_on = self.config.output.clone();
+        self.apply_placement(conn);
+    }
+
+    /// Set the anchor and margins of the layer surface, using the overrides of the output the
+    /// menu is placed on.
+    fn apply_placement(&mut self, conn: &mut Connection<Self>) {
+        let Some(layer_surface) = self.layer_surface else {
+            return;
+        };
+        let placement = self.config.placement(self.placed_on.as_deref());
+        if matches!(placement.anchor, config::ConfigAnchor::Cursor) && !self.pointers.is_empty() {
+            if self.placed_at_cursor {
+                return;
+            }
+            // Cover the whole output, the window is moved once the pointer enters the surface
+            self.locating_cursor = true;
+            layer_surface.set_anchor(
+                conn,
+                zwlr_layer_surface_v1::Anchor::Top
+                    | zwlr_layer_surface_v1::Anchor::Bottom
+                    | zwlr_layer_surface_v1::Anchor::Left
+                    | zwlr_layer_surface_v1::Anchor::Right,
+            );
+            layer_surface.set_margin(conn, 0, 0, 0, 0);
+            layer_surface.set_size(conn, 0, 0);
+            self.wl_surface.commit(conn);
+            return;
+        }
+        layer_surface.set_anchor(conn, placement.anchor.into());
+        self.apply_exclusive_zone(conn);
+        self.apply_margins(conn);
+        self.wl_surface.commit(conn);
+    }
+
+    /// Set the margins of the layer surface, reduced to keep the window on the output with
+    /// `clamp_to_output`.
+    fn apply_margins(&mut self, conn: &mut Connection<Self>) {
+        let Some(layer_surface) = self.layer_surface else {
+            return;
+        };
+        let mut placement = self.config.placement(self.placed_on.as_deref());
+        let output_size = self.current_output().and_then(Output::logical_size);
+        if let (true, Some((output_width, output_height))) =
+            (self.config.clamp_to_output, output_size)
+        {
+            let anchor = zwlr_layer_surface_v1::Anchor::from(placement.anchor);
+            let max_x = (output_width - self.width as i32).max(0);
+            let max_y = (output_height - self.height as i32).max(0);
+            let margins = [
+                (
+                    &mut placement.margin_top,
+                    anchor.contains(zwlr_layer_surface_v1::Anchor::Top),
+                    max_y,
+                ),
+                (
+                    &mut placement.margin_right,
+                    anchor.contains(zwlr_layer_surface_v1::Anchor::Right),
+                    max_x,
+                ),
+                (
+                    &mut placement.margin_bottom,
+                    anchor.contains(zwlr_layer_surface_v1::Anchor::Bottom),
+                    max_y,
+                ),
+                (
+                    &mut placement.margin_left,
+                    anchor.contains(zwlr_layer_surface_v1::Anchor::Left),
+                    max_x,
+                ),
+            ];
+            let mut clamped = false;
+            for (margin, anchored, max) in margins {
+                // Margins only move the window along the edges it is anchored to
+                if anchored && !(0..=max).contains(margin) {
+                    *margin = (*margin).clamp(0, max);
+                    clamped = true;
+                }
+            }
+            if clamped && !self.margins_clamped {
+                eprintln!("warning: the margins were reduced to keep the menu on the output");
+            }
+            self.margins_clamped = clamped;
+        }
+        layer_surface.set_margin(
+            conn,
+            placement.margin_top,
+            placement.margin_right,
+            placement.margin_bottom,
+            placement.margin_left,
+        );
+    }
+
+    /// Place the window at `(x, y)` on the output covered by the surface, keeping it on-screen.
+    fn place_at_cursor(&mut self, conn: &mut Connection<Self>, x: i32, y: i32) {
+        let Some(layer_surface) = self.layer_surface else {
+            return;
+        };
+        self.locating_cursor = false;
+        self.placed_at_cursor = true;
+        let (output_width, output_height) = (self.width as i32, self.height as i32);
+        (self.width, self.height) = self.surface_size();
+        self.dirty = true;
+
+        // The window itself starts after the shadow margin
+        let shadow_margin = self.config.shadow_margin() as i32;
+        let left = (x - shadow_margin)
+            .min(output_width - self.width as i32)
+            .max(0);
+        let top = (y - shadow_margin)
+            .min(output_height - self.height as i32)
+            .max(0);
+
+        layer_surface.set_anchor(
+            conn,
+            zwlr_layer_surface_v1::Anchor::Top | zwlr_layer_surface_v1::Anchor::Left,
+        );
+        layer_surface.set_margin(conn, top, 0, 0, left);
+        layer_surface.set_size(conn, self.width, self.height);
+        self.wl_surface.commit(conn);
+    }
+
+    /// Offer `text` as the clipboard selection of `seat`. The text is available as long as the
+    /// menu is open.
+    fn copy_to_clipboard(
+        &mut self,
+        conn: &mut Connection<Self>,
+        seat: WlSeat,
+        serial: u32,
+        text: String,
+    ) {
+        let Some(manager) = self.wl_data_device_manager else {
+            eprintln!("the compositor does not support the clipboard");
+            return;
+        };
+
+        let data_device = match self.data_devices.iter().find(|d| d.0 == seat) {
+            Some(&(_, data_device)) => data_device,
+            None => {
+                let data_device = manager.get_data_device(conn, seat);
+                self.data_devices.push((seat, data_device));
+                data_device
+            }
+        };
+
+        if let Some((old_source, _)) = self.clipboard.take() {
+            old_source.destroy(conn);
+        }
+        let source = manager.create_data_source_with_cb(conn, wl_data_source_cb);
+        source.offer(conn, wayrs_client::cstr!("text/plain;charset=utf-8").into());
+        source.offer(conn, wayrs_client::cstr!("text/plain").into());
+        data_device.set_selection(conn, Some(source), serial);
+        self.clipboard = Some((source, text));
+    }
+
+    /// Run the command of a selected entry, then do what `then` says. `sticky` keeps the menu
+    /// open instead of closing it. Returns whether the current page stays open.
+    #[allow(clippy::too_many_arguments)]
+    fn exec(
+        &mut self,
+        conn: &mut Connection<Self>,
+        cmd: &[String],
+        env: &[(&str, String)],
+        mut then: config::Then,
+        max_activations: Option<u32>,
+        refresh: bool,
+        sticky: bool,
+    ) -> bool {
+        if refresh {
+            // The descriptions can only show the effect once the command is done. A previous
+            // command which is still running is killed.
+            self.refresh = None;
+            match Capture::spawn(shell_command(cmd, env)) {
+                Ok(refresh) => self.refresh = Some(refresh),
+                Err(e) => eprintln!("failed to run the command: {e}"),
+            }
+        } else if let Err(e) = spawn_detached(shell_command(cmd, env)) {
+            eprintln!("failed to run the command: {e}");
+        }
+        if let Some(max) = max_activations {
+            let count = self
+                .activations
+                .entry((self.menu.current_page(), cmd.to_vec()))
+                .or_default();
+            *count += 1;
+            if *count >= max {
+                then = config::Then::Quit;
+            }
+        }
+        let parent = self.menu.parent_page();
+        if then.stays(sticky, parent.is_none()) {
+            return true;
+        }
+        match (then, parent) {
+            (config::Then::Parent, Some(parent)) => self.open_page(conn, parent),
+            (config::Then::Root, _) => self.open_page(conn, 0),
+            _ => self.exit = Some(ExitReason::Selected),
+        }
+        false
+    }
+
+    /// Start repeating `cmd` while `keycode` is held, using the configured repeat rate or the
+    /// compositor's one.
+    fn start_repeat(
+        &mut self,
+        keycode: xkb::Keycode,
+        info: Option<RepeatInfo>,
+        cmd: Vec<String>,
+        env: Vec<(&'static str, String)>,
+    ) {
+        let delay = self
+            .config
+            .repeat_delay
+            .map(Duration::from_millis)
+            .or(info.map(|info| info.delay));
+        let interval = self
+            .config
+            .repeat_interval
+            .map(Duration::from_millis)
+            .or(info.map(|info| info.interval));
+        if let (Some(delay), Some(interval)) = (delay, interval) {
+            if !interval.is_zero() {
+                self.repeat = Some(KeyRepeat {
+                    keycode,
+                    cmd,
+                    env,
+                    next: Instant::now() + delay,
+                    interval,
+                });
+            }
+        }
+    }
+
+    /// The size of the layer surface required to fit the current menu page, limited to the
+    /// height of the output. Taller pages are scrolled.
+    fn surface_size(&self) -> (u32, u32) {
+        let (width, height) = surface_size(&self.menu, &self.config);
+        let Some((_, output_height)) = self.current_output().and_then(Output::logical_size) else {
+            return (width, height);
+        };
+        let placement = self.config.placement(self.placed_on.as_deref());
+        let available =
+            output_height - placement.margin_top.max(0) - placement.margin_bottom.max(0);
+        (width, height.min(available.max(1) as u32))
+    }
+
+    /// The output the menu is shown on, if known.
+    fn current_output(&self) -> Option<&Output> {
+        self.outputs
+            .iter()
+            .find(|o| o.name.is_some() && o.name == self.placed_on)
+            .or_else(|| {
+                self.outputs
+                    .iter()
+                    .find(|o| self.visible_on_outputs.contains(&o.wl.id()))
+            })
+            .or(self.outputs.first().filter(|_| self.outputs.len() == 1))
+    }
+
+    /// With `auto_columns`, redistribute the entries into as many columns as fit on the output.
+    /// Returns whether the columns of the current page changed.
+    fn relayout(&mut self) -> bool {
+        if !self.config.auto_columns || self.config.layout == config::Layout::SingleColumn {
+            return false;
+        }
+        let Some((output_width, _)) = self.current_output().and_then(Output::logical_size) else {
+            return false;
+        };
+        let placement = self.config.placement(self.placed_on.as_deref());
+        let mut max_width = output_width as f64
+            - placement.margin_left.max(0) as f64
+            - placement.margin_right.max(0) as f64;
+        if let Some(config_max_width) = self.config.max_width {
+            max_width = max_width.min(config_max_width);
+        }
+        let window_extra = (self.config.padding() + self.config.border_width) * 2.0
+            + self.config.shadow_margin() * 2.0;
+        self.menu.relayout(max_width - window_extra)
+    }
+
+    /// Update the window after the output it is shown on changed, redrawing it only if the
+    /// layout, the size or the buffer scale changed.
+    fn fit_to_output(&mut self, conn: &mut Connection<Self>) {
+        if self.relayout() || self.surface_size() != (self.width, self.height) {
+            self.resize(conn);
+        } else {
+            self.draw(conn);
+        }
+    }
+
+    /// How far the current page can be scrolled down.
+    fn max_scroll(&self) -> f64 {
+        let window_extra = (self.config.padding() + self.config.border_width) * 2.0
+            + self.config.shadow_margin() * 2.0;
+        (self.menu.height() + window_extra - self.height as f64).max(0.0)
+    }
+
+    /// Do what the selected entry does. `sticky` keeps the menu open after running a command, and
+    /// `input` is the seat and serial of the input event, needed to copy to the clipboard.
+    /// Returns whether the current page stays open.
+    fn activate(
+        &mut self,
+        conn: &mut Connection<Self>,
+        action: menu::Action,
+        sticky: bool,
+        input: Option<(WlSeat, u32)>,
+    ) -> bool {
+        if let menu::Action::Exec {
+            notify: Some(message),
+            ..
+        } = &action
+        {
+            // The notification daemon may be missing, which is not worth failing for
+            let mut proc = Command::new("notify-send");
+            proc.args(["wlr-which-key", message]);
+            let _ = spawn_detached(proc);
+        }
+
+        match action {
+            menu::Action::Quit => {
+                self.exit = Some(ExitReason::Cancelled);
+                conn.break_dispatch_loop();
+                false
+            }
+            menu::Action::Exec {
+                cmd,
+                capture: true,
+                env,
+                ..
+            } => {
+                // The previous command is killed
+                self.capture = None;
+                match Capture::spawn(shell_command(&cmd, &env)) {
+                    Ok(capture) => self.capture = Some(capture),
+                    Err(e) => {
+                        let output = format!("failed to run command: {e}");
+                        self.menu.show_output(&output, &self.config);
+                        self.resize(conn);
+                    }
+                }
+                true
+            }
+            menu::Action::Exec {
+                cmd,
+                then,
+                max_activations,
+                refresh,
+                env,
+                ..
+            } => self.exec(conn, &cmd, &env, then, max_activations, refresh, sticky),
+            menu::Action::Submenu(page) => {
+                self.open_page(conn, page);
+                false
+            }
+            menu::Action::CopyPage => {
+                if let Some((seat, serial)) = input {
+                    self.copy_to_clipboard(conn, seat, serial, self.menu.page_text());
+                }
+                true
+            }
+        }
+    }
+
+    /// Select the entry bound to `key` on the current page without pressing it, if there is one.
+    /// `input` is the seat and serial of the input event, if it has them. Returns whether the
+    /// current page stays open.
+    fn select_key(
+        &mut self,
+        conn: &mut Connection<Self>,
+        key: &key::Key,
+        input: Option<(WlSeat, u32)>,
+    ) -> bool {
+        match self.menu.key_action(key) {
+            Some(action) => self.activate(conn, action, self.sticky_held, input),
+            None => false,
+        }
+    }
+
+    /// Select the `scroll_up` or `scroll_down` entry once per wheel step of `delta`. Returns
+    /// whether such an entry exists on the current page.
+    fn wheel_select(&mut self, conn: &mut Connection<Self>, delta: f64) -> bool {
+        let key = match delta < 0.0 {
+            true => &self.config.scroll_up,
+            false => &self.config.scroll_down,
+        };
+        let Some(key) = key
+            .clone()
+            .filter(|key| self.menu.key_action(key).is_some())
+        else {
+            return false;
+        };
+
+        // Reset when changing direction, touchpads send many small steps
+        if self.wheel * delta < 0.0 {
+            self.wheel = 0.0;
+        }
+        self.wheel += delta;
+        while self.wheel.abs() >= WHEEL_STEP && self.exit.is_none() {
+            self.wheel -= WHEEL_STEP.copysign(delta);
+            self.last_activity = Instant::now();
+            if !self.select_key(conn, &key, None) {
+                self.wheel = 0.0;
+            }
+        }
+        true
+    }
+
+    /// Scroll the current page by `delta`, if it is taller than the window.
+    fn scroll_by(&mut self, conn: &mut Connection<Self>, delta: f64) {
+        let scroll = (self.scroll + delta).clamp(0.0, self.max_scroll());
+        if scroll != self.scroll {
+            self.scroll = scroll;
+            self.redraw(conn);
+        }
+    }
+
+    /// Resize the surface to fit the current menu page.
+    fn resize(&mut self, conn: &mut Connection<Self>) {
+        if self.locating_cursor {
+            self.redraw(conn);
+            return;
+        }
+
+        self.scroll = 0.0;
+        let size = self.surface_size();
+        if size == (self.width, self.height) {
+            // No new configure event will arrive
+            self.redraw(conn);
+            return;
+        }
+        (self.width, self.height) = size;
+        // Drawn once the compositor configures the new size
+        self.dirty = true;
+
+        if let Some(layer_surface) = self.layer_surface {
+            layer_surface.set_size(conn, self.width, self.height);
+        }
+        if !self.placed_at_cursor {
+            self.apply_margins(conn);
+        }
+        self.apply_exclusive_zone(conn);
+        self.wl_surface.commit(conn);
+    }
+
+    /// Reserve space for the menu along the anchored edge if `exclusive_zone` is set. With
+    /// `auto`, the size of the surface is reserved.
+    fn apply_exclusive_zone(&self, conn: &mut Connection<Self>) {
+        let (Some(layer_surface), Some(zone)) = (self.layer_surface, self.config.exclusive_zone)
+        else {
+            return;
+        };
+        let zone = match zone {
+            config::ExclusiveZone::Fixed(zone) => zone,
+            config::ExclusiveZone::Auto(_) => {
+                match self.config.placement(self.placed_on.as_deref()).anchor {
+                    config::ConfigAnchor::Top | config::ConfigAnchor::Bottom => self.height as i32,
+                    config::ConfigAnchor::Left | config::ConfigAnchor::Right => self.width as i32,
+                    _ => 0,
+                }
+            }
+        };
+        layer_surface.set_exclusive_zone(conn, zone);
+    }
+
+    fn bind_output(&mut self, conn: &mut Connection<Self>, global: &Global) {
+        let wl: WlOutput = global.bind_with_cb(conn, 1..=4, wl_output_cb).unwrap();
+        self.outputs.push(Output::new(wl, global.name));
+    }
+}
+
+impl SeatHandler for State {
+    fn get_seats(&mut self) -> &mut Seats {
+        &mut self.seats
+    }
+
+    fn keyboard_added(&mut self, conn: &mut Connection<Self>, seat: WlSeat) {
+        self.keyboards.push(Keyboard::new(conn, seat));
+    }
+
+    fn keyboard_removed(&mut self, conn: &mut Connection<Self>, seat: WlSeat) {
+        let i = self
+            .keyboards
+            .iter()
+            .position(|k| k.seat() == seat)
+            .unwrap();
+        let keyboard = self.keyboards.swap_remove(i);
+        keyboard.destroy(conn);
+    }
+
+    fn pointer_added(&mut self, conn: &mut Connection<Self>, seat: WlSeat) {
+        let pointer = seat.get_pointer_with_cb(conn, wl_pointer_cb);
+        self.pointers.push((seat, pointer));
+    }
+
+    fn pointer_removed(&mut self, conn: &mut Connection<Self>, seat: WlSeat) {
+        let i = self.pointers.iter().position(|p| p.0 == seat).unwrap();
+        let (_, pointer) = self.pointers.swap_remove(i);
+        if pointer.version() >= 3 {
+            pointer.release(conn);
+        }
+    }
+}
+
+impl KeyboardHandler for State {
+    fn get_keyboard(&mut self, wl_keyboard: WlKeyboard) -> &mut Keyboard {
+        self.keyboards
+            .iter_mut()
+            .find(|k| k.wl_keyboard() == wl_keyboard)
+            .unwrap()
+    }
+
+    fn key_presed(&mut self, conn: &mut Connection<Self>, event: KeyboardEvent) {
+        // Pressing another key stops the repeat, like in text editors
+        self.repeat = None;
+        self.last_activity = Instant::now();
+        if let Some(modifier) = self.config.sticky_modifier {
+            self.sticky_held |= modifier.is_set_by(event.keysym);
+        }
+        // Any other key turns the modifier into a part of a combination
+        self.lone_modifier = self
+            .config
+            .modifier_actions
+            .keys()
+            .copied()
+            .find(|modifier| modifier.is_set_by(event.keysym));
+
+        // The sticky modifier does not take part in matching, it only keeps the menu open
+        let sticky = self
+            .config
+            .sticky_modifier
+            .filter(|m| m.is_active(&event.xkb_state));
+
+        let (mut xkb_state, mut keysym) = key::match_state(
+            event.xkb_state,
+            event.keycode,
+            event.keysym,
+            sticky,
+            self.config.ignore_lock_mods,
+        );
+
+        if self.config.numpad_as_digits {
+            keysym = key::numpad_to_main(keysym);
+        }
+
+        // Look for a layout in which this key is bound
+        if self.config.kbd_layout_mode == key::LayoutMode::Any
+            && !self.menu.is_bound(&xkb_state, keysym)
+        {
+            let other = key::find_in_other_layouts(&xkb_state, event.keycode, |state, sym| {
+                self.menu.is_bound(state, sym)
+            });
+            if let Some(other) = other {
+                (xkb_state, keysym) = other;
+            }
+        }
+
+        // Dead keys and compose sequences which are not bound on their own are combined into the
+        // composed character
+        let bound = self.menu.is_bound(&xkb_state, keysym);
+        let Some(keysym) = key::compose(self.compose.as_mut(), keysym, bound) else {
+            return;
+        };
+
+        // Tab and Shift+Tab switch between the menus of `cycle_menus`, unless bound
+        if self.menu_cycle.len() > 1 && !self.menu.is_bound(&xkb_state, keysym) {
+            match keysym.raw() {
+                xkb::keysyms::KEY_Tab => return self.cycle_menu(conn, 1),
+                xkb::keysyms::KEY_ISO_Left_Tab => {
+                    return self.cycle_menu(conn, self.menu_cycle.len() - 1);
+                }
+                _ => (),
+            }
+        }
+
+        // Page_Up and Page_Down scroll pages which do not fit on the output, unless bound
+        if self.max_scroll() > 0.0 && !self.menu.is_bound(&xkb_state, keysym) {
+            let step = self.height as f64 * 0.8;
+            match keysym.raw() {
+                xkb::keysyms::KEY_Page_Down => return self.scroll_by(conn, step),
+                xkb::keysyms::KEY_Page_Up => return self.scroll_by(conn, -step),
+                _ => (),
+            }
+        }
+
+        if let Some(action) = self.menu.get_action(&xkb_state, keysym) {
+            let repeat = match &action {
+                menu::Action::Exec { cmd, env, .. } if action.repeats() => {
+                    Some((cmd.clone(), env.clone()))
+                }
+                _ => None,
+            };
+            let input = Some((event.seat, event.serial));
+            let stays = self.activate(conn, action, sticky.is_some(), input);
+            if let Some((cmd, env)) = repeat {
+                if stays && xkb_state.get_keymap().key_repeats(event.keycode) {
+                    self.start_repeat(event.keycode, event.repeat_info, cmd, env);
+                }
+            }
+        }
+    }
+
+    fn enter_surface(&mut self, _: &mut Connection<Self>, _: WlKeyboard, args: EnterArgs) {
+        if args.surface == self.wl_surface.id() {
+            self.had_keyboard_focus = true;
+        }
+    }
+
+    fn leave_surface(&mut self, conn: &mut Connection<Self>, _: WlKeyboard, args: LeaveArgs) {
+        if args.surface != self.wl_surface.id() {
+            return;
+        }
+        // The key will not be released on our surface
+        self.repeat = None;
+        self.lone_modifier = None;
+        self.sticky_held = false;
+        // Ignore leaves before the menu got focus, some compositors send them while configuring
+        if self.config.close_on_focus_loss && self.had_keyboard_focus {
+            self.exit = Some(ExitReason::Closed);
+            conn.break_dispatch_loop();
+        }
+    }
+
+    fn key_released(&mut self, conn: &mut Connection<Self>, event: KeyboardEvent) {
+        if self
+            .repeat
+            .as_ref()
+            .is_some_and(|repeat| repeat.keycode == event.keycode)
+        {
+            self.repeat = None;
+        }
+
+        if let Some(modifier) = self.config.sticky_modifier {
+            self.sticky_held &= !modifier.is_set_by(event.keysym);
+        }
+
+        if let Some(modifier) = self.lone_modifier.take() {
+            if modifier.is_set_by(event.keysym) {
+                let key = self.config.modifier_actions[&modifier].clone();
+                self.select_key(conn, &key, Some((event.seat, event.serial)));
+            }
+        }
+
+        if let Some(modifier) = self.config.close_on_release {
+            if modifier.is_set_by(event.keysym) {
+                self.exit = Some(ExitReason::Cancelled);
+                conn.break_dispatch_loop();
+            }
+        }
+    }
+}
+
+/// Connect to the compositor just to print its outputs, for `--list-outputs`.
+fn list_outputs() -> anyhow::Result<()> {
+    let mut conn = Connection::<Vec<Output>>::connect()?;
+    conn.blocking_roundtrip()?;
+    conn.add_registry_cb(|conn, outputs, event| {
+        if let wl_registry::Event::Global(g) = event {
+            if g.is::<WlOutput>() {
+                let wl: WlOutput = g
+                    .bind_with_cb(conn, 1..=4, |ctx: EventCtx<Vec<Output>, WlOutput>| {
+                        let output = ctx.state.iter_mut().find(|o| o.wl == ctx.proxy).unwrap();
+                        output.update(ctx.event);
+                    })
+                    .unwrap();
+                outputs.push(Output::new(wl, g.name));
+            }
+        }
+    });
+
+    // Bind the outputs, then receive their names
+    let mut outputs = Vec::new();
+    conn.dispatch_events(&mut outputs);
+    conn.blocking_roundtrip()?;
+    conn.dispatch_events(&mut outputs);
+    print_outputs(&outputs);
+    Ok(())
+}
+
+/// Print a table of outputs for `--list-outputs`.
+fn print_outputs(outputs: &[Output]) {
+    println!(
+        "{:<12} {:<40} {:<12} {:<5}",
+        "NAME", "MAKE/MODEL", "SIZE", "SCALE"
+    );
+    for output in outputs {
+        let make_model = output
+            .description
+            .clone()
+            .unwrap_or_else(|| format!("{} {}", output.make, output.model));
+        let size = match output.logical_size() {
+            Some((width, height)) => format!("{width}x{height}"),
+            None => "-".into(),
+        };
+        println!(
+            "{:<12} {:<40} {:<12} {:<5}",
+            output.name.as_deref().unwrap_or("-"),
+            make_model,
+            size,
+            output.scale,
+        );
+    }
+}
+
+/// Remember the key path of the current page, one key per line.
+fn save_last_page(path: &Path, page_path: &[String]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, page_path.join("\n"))
+}
+
+/// Run `proc` in the background, detached from this process.
+fn spawn_detached(mut proc: Command) -> io::Result<()> {
+    proc.stdin(Stdio::null());
+    proc.stdout(Stdio::null());
+    // Safety: libc::daemon() is async-signal-safe
+    unsafe {
+        proc.pre_exec(|| match libc::daemon(1, 0) {
+            -1 => Err(io::Error::other("Failed to detach new process")),
+            _ => Ok(()),
+        });
+    }
+    proc.spawn()?.wait()?;
+    Ok(())
+}
+
+/// A shell running `cmds` in order, with the environment variables `env` describing the
+/// selected entry.
+fn shell_command(cmds: &[String], env: &[(&str, String)]) -> Command {
+    let mut proc = Command::new("sh");
+    proc.args(["-c", &chain_commands(cmds)]);
+    proc.envs(env.iter().map(|(name, value)| (name, value)));
+    proc
+}
+
+/// Join commands into a single shell script which runs them in order, stopping at the first
+/// failure.
+fn chain_commands(cmds: &[String]) -> String {
+    match cmds {
+        [cmd] => cmd.clone(),
+        cmds => cmds
+            .iter()
+            .map(|cmd| format!("{{ {cmd}\n}}"))
+            .collect::<Vec<_>>()
+            .join(" && "),
+    }
+}
+
+/// Render every page of the menu to `path` and files named like it, without a compositor.
+fn render_to_png(
+    path: &Path,
+    config: &config::Config,
+    menu: &mut menu::Menu,
+    background_image: Option<&cairo::ImageSurface>,
+) -> anyhow::Result<()> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    for page in 0..menu.page_count() {
+        menu.set_page(page);
+        let (width, height) = surface_size(menu, config);
+        let surface =
+            cairo::ImageSurface::create(cairo::Format::ARgb32, width as i32, height as i32)?;
+        let cairo_ctx = cairo::Context::new(&surface)?;
+        render_window(
+            &cairo_ctx,
+            width as f64,
+            height as f64,
+            1.0,
+            None,
+            0.0,
+            config,
+            menu,
+            background_image,
+        );
+        drop(cairo_ctx);
+
+        let page_path = match page {
+            0 => path.to_owned(),
+            _ => path.with_file_name(format!("{stem}-{page}.png")),
+        };
+        let mut file = File::create(&page_path)
+            .with_context(|| format!("Failed to create {}", page_path.display()))?;
+        surface
+            .write_to_png(&mut file)
+            .with_context(|| format!("Failed to write {}", page_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Render the window, including the shadow, to a `width`x`height` area at the origin. `alpha`
+/// is applied on top of the configured opacity. `transition` is the page being switched away
+/// from and the progress of the transition animation. `scroll` is how far the menu is scrolled
+/// down if it does not fit into `height`.
+#[allow(clippy::too_many_arguments)]
+fn render_window(
+    cairo_ctx: &cairo::Context,
+    width: f64,
+    height: f64,
+    alpha: f64,
+    transition: Option<(usize, f64)>,
+    scroll: f64,
+    config: &config::Config,
+    menu: &menu::Menu,
+    background_image: Option<&cairo::ImageSurface>,
+) {
+    // Render everything into a group so that the opacity applies to the window as a whole
+    let opacity = config.opacity.clamp(0.0, 1.0) * alpha;
+    if opacity < 1.0 {
+        cairo_ctx.push_group();
+    }
+
+    // The window itself is inset by the shadow margin on every side
+    let shadow_margin = config.shadow_margin();
+    cairo_ctx.translate(shadow_margin, shadow_margin);
+    let width_f = width - shadow_margin * 2.0;
+    let height_f = height - shadow_margin * 2.0;
+
+    // drop shadow, approximated by stacking translucent rounded rectangles
+    if shadow_margin > 0.0 {
+        let blur = config.shadow_blur;
+        let [offset_x, offset_y] = config.shadow_offset;
+        let steps = blur.ceil().max(1.0);
+        cairo_ctx.save().unwrap();
+        config.shadow_color.apply(cairo_ctx);
+        for i in 0..steps as u32 {
+            let grow = blur * (1.0 - i as f64 / steps);
+            rounded_rect(
+                cairo_ctx,
+                offset_x - grow,
+                offset_y - grow,
+                width_f + grow * 2.0,
+                height_f + grow * 2.0,
+                config.corner_r.grow(grow),
+            );
+            cairo_ctx.clip();
+            cairo_ctx.paint_with_alpha(1.0 / steps).unwrap();
+            cairo_ctx.reset_clip();
+        }
+        cairo_ctx.restore().unwrap();
+    }
+
+    // The path runs through the middle of the border, so that the stroke stays inside the
+    // window and its outer edge is rounded by exactly `corner_r`
+    let half_border = config.border_width.max(0.0) * 0.5;
+    rounded_rect(
+        cairo_ctx,
+        half_border,
+        half_border,
+        width_f - half_border * 2.0,
+        height_f - half_border * 2.0,
+        config.corner_r.grow(-half_border),
+    );
+    config.background.apply(cairo_ctx, width_f, height_f);
+    cairo_ctx.fill_preserve().unwrap();
+    if let Some(image) = background_image {
+        cairo_ctx.save().unwrap();
+        cairo_ctx.clip_preserve();
+        paint_image(
+            cairo_ctx,
+            image,
+            config.background_image_fit,
+            width_f,
+            height_f,
+        );
+        cairo_ctx.restore().unwrap();
+    }
+    config.border.apply(cairo_ctx);
+    cairo_ctx.set_line_width(config.border_width);
+    cairo_ctx.stroke().unwrap();
+
+    // draw our menu, clipped if it does not fit into `max_width` or the output
+    let inset = config.padding() + config.border_width;
+    let content_width = (width_f - inset * 2.0).max(0.0);
+    let content_height = (height_f - inset * 2.0).max(0.0);
+    let overflows = menu.height() > content_height;
+    if overflows {
+        draw_scrollbar(
+            cairo_ctx,
+            config,
+            width_f - config.border_width - config.padding() * 0.5,
+            inset,
+            content_height,
+            menu.height(),
+            scroll,
+        );
+    }
+    cairo_ctx.save().unwrap();
+    if menu.width() > content_width || overflows || transition.is_some() {
+        cairo_ctx.rectangle(inset, inset, content_width, content_height);
+        cairo_ctx.clip();
+    }
+    if overflows {
+        cairo_ctx.translate(0.0, -scroll);
+    }
+    let dx = config
+        .content_align
+        .offset(content_width, menu.width())
+        .max(0.0);
+    match transition {
+        None => menu.render(config, cairo_ctx, inset + dx, inset).unwrap(),
+        Some((from, progress)) => {
+            let from_dx = config
+                .content_align
+                .offset(content_width, menu.page_width(from))
+                .max(0.0);
+            match config.transition {
+                config::Transition::Slide => {
+                    // Slide towards the left when entering a submenu
+                    let direction = match menu.is_submenu_of(menu.current_page(), from) {
+                        true => -1.0,
+                        false => 1.0,
+                    };
+                    let offset = (content_width + inset) * direction;
+                    menu.render_page(
+                        config,
+                        cairo_ctx,
+                        from,
+                        inset + from_dx + offset * progress,
+                        inset,
+                    )
+                    .unwrap();
+                    menu.render(
+                        config,
+                        cairo_ctx,
+                        inset + dx - offset * (1.0 - progress),
+                        inset,
+                    )
+                    .unwrap();
+                }
+                config::Transition::Fade | config::Transition::None => {
+                    cairo_ctx.push_group();
+                    menu.render_page(config, cairo_ctx, from, inset + from_dx, inset)
+                        .unwrap();
+                    cairo_ctx.pop_group_to_source().unwrap();
+                    cairo_ctx.paint_with_alpha(1.0 - progress).unwrap();
+                    cairo_ctx.push_group();
+                    menu.render(config, cairo_ctx, inset + dx, inset).unwrap();
+                    cairo_ctx.pop_group_to_source().unwrap();
+                    cairo_ctx.paint_with_alpha(progress).unwrap();
+                }
+            }
+        }
+    }
+    cairo_ctx.restore().unwrap();
+
+    if opacity < 1.0 {
+        cairo_ctx.pop_group_to_source().unwrap();
+        cairo_ctx.paint_with_alpha(opacity).unwrap();
+    }
+}
+
+/// Draw a vertical scrollbar at `x` for a `view_height` tall view, starting at `y`, of
+/// `content_height` tall content scrolled down by `scroll`.
+fn draw_scrollbar(
+    cairo_ctx: &cairo::Context,
+    config: &config::Config,
+    x: f64,
+    y: f64,
+    view_height: f64,
+    content_height: f64,
+    scroll: f64,
+) {
+    let thumb_height = view_height * view_height / content_height;
+    let thumb_y = y + view_height * scroll / content_height;
+    cairo_ctx.save().unwrap();
+    config.border.apply(cairo_ctx);
+    cairo_ctx.set_line_width(2.0);
+    cairo_ctx.set_line_cap(cairo::LineCap::Round);
+    cairo_ctx.move_to(x, thumb_y);
+    cairo_ctx.line_to(x, thumb_y + thumb_height);
+    cairo_ctx.stroke().unwrap();
+    cairo_ctx.restore().unwrap();
+}
+
+/// Compute the size of the layer surface required to fit the current menu page.
+fn surface_size(menu: &menu::Menu, config: &config::Config) -> (u32, u32) {
+    let (menu_width, menu_height) = match config.fixed_size {
+        Some(config::FixedSize::Largest) => {
+            let (width, height) = menu.largest_size();
+            // Captured output may be larger than any page
+            (width.max(menu.width()), height.max(menu.height()))
+        }
+        None => (menu.width(), menu.height()),
+    };
+    let window_extra = (config.padding() + config.border_width) * 2.0;
+    let mut window_width = menu_width + window_extra;
+    if let Some(max_width) = config.max_width {
+        window_width = window_width.min(max_width);
+    }
+    if let Some(min_width) = config.min_width {
+        window_width = window_width.max(min_width);
+    }
+
+    // Round up, so that the border is not cut off by fractional text sizes
+    let shadow = config.shadow_margin() * 2.0;
+    let width = (window_width + shadow).ceil() as u32;
+    let height = (menu_height + window_extra + shadow).ceil() as u32;
+    (width, height)
+}
+
+/// A region covering the rectangle `(x, y, width, height)` with rounded corners, approximated
+/// with one-pixel rows.
+fn rounded_region(
+    conn: &mut Connection<State>,
+    wl_compositor: WlCompositor,
+    (x, y, width, height): (i32, i32, i32, i32),
+    r: config::CornerRadius,
+) -> WlRegion {
+    // How far the row `dy` pixels away from the edge is indented by a corner of radius `r`
+    let indent = |r: f64, dy: f64| {
+        if dy >= r {
+            0
+        } else {
+            (r - (r * r - (r - dy) * (r - dy)).sqrt()).round() as i32
+        }
+    };
+    let r = r.fit(width as f64, height as f64);
+    let region = wl_compositor.create_region(conn);
+    let top = (r.top_left.max(r.top_right).ceil() as i32).min(height / 2);
+    let bottom = (r.bottom_left.max(r.bottom_right).ceil() as i32).min(height - top);
+    for row in 0..top {
+        let dy = row as f64 + 0.5;
+        let (left, right) = (indent(r.top_left, dy), indent(r.top_right, dy));
+        region.add(conn, x + left, y + row, width - left - right, 1);
+    }
+    region.add(conn, x, y + top, width, height - top - bottom);
+    for row in 0..bottom {
+        let dy = row as f64 + 0.5;
+        let (left, right) = (indent(r.bottom_left, dy), indent(r.bottom_right, dy));
+        region.add(
+            conn,
+            x + left,
+            y + height - 1 - row,
+            width - left - right,
+            1,
+        );
+    }
+    region
+}
+
+/// Add a rectangle with rounded corners as a new sub-path.
+fn rounded_rect(
+    cairo_ctx: &cairo::Context,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    r: config::CornerRadius,
+) {
+    let r = r.fit(width, height);
+    cairo_ctx.new_sub_path();
+    cairo_ctx.arc(
+        x + r.top_left,
+        y + r.top_left,
+        r.top_left,
+        PI,
+        3.0 * FRAC_PI_2,
+    );
+    cairo_ctx.arc(
+        x + width - r.top_right,
+        y + r.top_right,
+        r.top_right,
+        3.0 * FRAC_PI_2,
+        TAU,
+    );
+    cairo_ctx.arc(
+        x + width - r.bottom_right,
+        y + height - r.bottom_right,
+        r.bottom_right,
+        0.0,
+        FRAC_PI_2,
+    );
+    cairo_ctx.arc(
+        x + r.bottom_left,
+        y + height - r.bottom_left,
+        r.bottom_left,
+        FRAC_PI_2,
+        PI,
+    );
+    cairo_ctx.close_path();
+}
+
+/// Wait until any of `fds` is readable or `timeout` elapses (forever if `None`). Returns which of
+/// `fds` are readable; negative fds are ignored.
+fn poll_readable<const N: usize>(
+    fds: [RawFd; N],
+    timeout: Option<Duration>,
+) -> io::Result<[bool; N]> {
+    let mut pollfds = fds.map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    let timeout = timeout.map_or(-1, |timeout| {
+        timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX)
+    });
+    loop {
+        match unsafe { libc::poll(pollfds.as_mut_ptr(), N as libc::nfds_t, timeout) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            _ => return Ok(pollfds.map(|pollfd| pollfd.revents != 0)),
+        }
+    }
+}
+
+/// Load a PNG image from the given path.
+fn load_image(path: &Path) -> anyhow::Result<cairo::ImageSurface> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open image: {}", path.display()))?;
+    cairo::ImageSurface::create_from_png(&mut file)
+        .with_context(|| format!("Failed to load PNG image: {}", path.display()))
+}
+
+/// The path of the high resolution variant of an image: `logo.png` becomes `logo@2x.png`.
+fn hidpi_variant(path: &Path) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    name.push("@2x");
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Paint `image` over the `width`x`height` area at the origin, respecting the current clip.
+fn paint_image(
+    cairo_ctx: &cairo::Context,
+    image: &cairo::ImageSurface,
+    fit: config::BackgroundImageFit,
+    width: f64,
+    height: f64,
+) {
+    let image_w = image.width() as f64;
+    let image_h = image.height() as f64;
+
+    cairo_ctx.save().unwrap();
+    match fit {
+        config::BackgroundImageFit::Tile => {
+            let pattern = cairo::SurfacePattern::create(image);
+            pattern.set_extend(cairo::Extend::Repeat);
+            cairo_ctx.set_source(&pattern).unwrap();
+        }
+        config::BackgroundImageFit::Cover | config::BackgroundImageFit::Contain => {
+            let scale_x = width / image_w;
+            let scale_y = height / image_h;
+            let scale = match fit {
+                config::BackgroundImageFit::Cover => scale_x.max(scale_y),
+                _ => scale_x.min(scale_y),
+            };
+            cairo_ctx.translate(
+                (width - image_w * scale) * 0.5,
+                (height - image_h * scale) * 0.5,
+            );
+            cairo_ctx.scale(scale, scale);
+            cairo_ctx.set_source_surface(image, 0.0, 0.0).unwrap();
+        }
+    }
+    cairo_ctx.paint().unwrap();
+    cairo_ctx.restore().unwrap();
+}
+
+fn wl_registry_cb(conn: &mut Connection<State>, state: &mut State, event: &wl_registry::Event) {
+    match event {
+        wl_registry::Event::Global(g) if g.is::<WlOutput>() => state.bind_output(conn, g),
+        wl_registry::Event::GlobalRemove(name) => {
+            if let Some(output_i) = state.outputs.iter().position(|o| o.reg_name == *name) {
+                let output = state.outputs.swap_remove(output_i);
+                state.visible_on_outputs.remove(&output.wl.id());
+                if output.wl.version() >= 3 {
+                    output.wl.release(conn);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn wl_output_cb(ctx: EventCtx<State, WlOutput>) {
+    let output = ctx
+        .state
+        .outputs
+        .iter_mut()
+        .find(|o| o.wl == ctx.proxy)
+        .unwrap();
+    let named = matches!(ctx.event, wl_output::Event::Name(_));
+    if output.update(ctx.event) {
+        ctx.state.fit_to_output(ctx.conn);
+    }
+    if named && ctx.state.waiting_for_output {
+        ctx.state.create_layer_surface(ctx.conn);
+    }
+}
+
+fn wl_surface_cb(ctx: EventCtx<State, WlSurface>) {
+    assert_eq!(ctx.proxy, ctx.state.wl_surface);
+    match ctx.event {
+        wl_surface::Event::Enter(output) => {
+            ctx.state.visible_on_outputs.insert(output);
+            if ctx.state.config.output.is_none() {
+                let name = ctx
+                    .state
+                    .outputs
+                    .iter()
+                    .find(|o| o.wl.id() == output)
+                    .and_then(|o| o.name.clone());
+                if name.is_some() && name != ctx.state.placed_on {
+                    ctx.state.placed_on = name;
+                    ctx.state.apply_placement(ctx.conn);
+                }
+            }
+            // The page may not fit on this output
+            ctx.state.fit_to_output(ctx.conn);
+        }
+        wl_surface::Event::Leave(output) => {
+            ctx.state.visible_on_outputs.remove(&output);
+        }
+        wl_surface::Event::PreferredBufferScale(scale) => {
+            assert!(scale >= 1);
+            let scale = scale as u32;
+            if ctx.state.surface_scale != scale {
+                ctx.state.surface_scale = scale;
+                // Redrawn at the new scale
+                ctx.state.draw(ctx.conn);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn wl_data_source_cb(ctx: EventCtx<State, WlDataSource>) {
+    match ctx.event {
+        wl_data_source::Event::Send(args) => {
+            if let Some((_, text)) = ctx.state.clipboard.as_ref().filter(|c| c.0 == ctx.proxy) {
+                // The receiving client may have gone away, which is not our problem
+                let _ = File::from(args.fd).write_all(text.as_bytes());
+            }
+        }
+        wl_data_source::Event::Cancelled => {
+            ctx.proxy.destroy(ctx.conn);
+            if ctx
+                .state
+                .clipboard
+                .as_ref()
+                .is_some_and(|c| c.0 == ctx.proxy)
+            {
+                ctx.state.clipboard = None;
+            }
+        }
+        _ => (),
+    }
+}
+
+fn wl_pointer_cb(ctx: EventCtx<State, WlPointer>) {
+    match ctx.event {
+        wl_pointer::Event::Enter(args)
+            if ctx.state.locating_cursor && args.surface == ctx.state.wl_surface.id() =>
+        {
+            let (x, y) = (args.surface_x.as_int(), args.surface_y.as_int());
+            ctx.state.place_at_cursor(ctx.conn, x, y);
+        }
+        wl_pointer::Event::Button(args)
+            if ctx.state.close_on_click && args.state == wl_pointer::ButtonState::Pressed =>
+        {
+            ctx.state.exit = Some(ExitReason::Cancelled);
+            ctx.conn.break_dispatch_loop();
+        }
+        wl_pointer::Event::Axis(args)
+            if args.axis == wl_pointer::Axis::VerticalScroll
+                && !ctx.state.wheel_select(ctx.conn, args.value.as_f64()) =>
+        {
+            ctx.state.scroll_by(ctx.conn, args.value.as_f64());
+        }
+        _ => (),
+    }
+}
+
+fn layer_surface_cb(ctx: EventCtx<State, ZwlrLayerSurfaceV1>) {
+    assert_eq!(Some(ctx.proxy), ctx.state.layer_surface);
+    match ctx.event {
+        zwlr_layer_surface_v1::Event::Configure(args) => {
+            if args.width != 0 && args.width != ctx.state.width {
                 ctx.state.width = args.width;
+                ctx.state.dirty = true;
             }
-            if args.height != 0 {
+            if args.height != 0 && args.height != ctx.state.height {
                 ctx.state.height = args.height;
+                ctx.state.dirty = true;
             }
             ctx.state.configured = true;
             ctx.proxy.ack_configure(ctx.conn, args.serial);
-            ctx.state.draw(ctx.conn);
+            if ctx.state.dirty {
+                ctx.state.draw(ctx.conn);
+            } else {
+                // Nothing to draw, but the configure only applies with a commit
+                ctx.state.wl_surface.commit(ctx.conn);
+            }
         }
         zwlr_layer_surface_v1::Event::Closed => {
-            ctx.state.exit = true;
+            ctx.state.exit = Some(ExitReason::Closed);
             ctx.conn.break_dispatch_loop();
         }
         _ => (),
@@ -1,37 +1,145 @@
-use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::mem;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
 use pangocairo::{cairo, pango};
+use serde::Serialize;
 use wayrs_utils::keyboard::xkb;
 
 use crate::config::{self, Config};
-use crate::key::Key;
-use crate::text::{self, ComputedText};
+use crate::key::{Key, SingleKey};
+use crate::text::{self, ComputedText, TextCache};
+
+/// The maximum number of lines of captured command output shown in the menu.
+const MAX_OUTPUT_LINES: usize = 40;
+
+/// The maximum number of characters of a line of captured command output shown in the menu.
+const MAX_OUTPUT_LINE_CHARS: usize = 200;
+
+/// The space between the header image and the entries.
+const HEADER_GAP: f64 = 8.0;
 
 pub struct Menu {
     pages: Vec<MenuPage>,
     cur_page: usize,
     separator: ComputedText,
+    /// Keys pressed so far which form a prefix of a key sequence.
+    pending: Vec<SingleKey>,
+    case_insensitive: bool,
+    cancel_keys: Vec<SingleKey>,
+    parent_keys: Vec<SingleKey>,
+    copy_key: Option<SingleKey>,
+    column_padding: f64,
+    /// Captured command output, shown instead of the current page until a key is pressed.
+    output: Option<ComputedText>,
+    /// Outputs of the commands substituted into descriptions, `None` if the command failed.
+    desc_cmd_cache: HashMap<String, Option<String>>,
+    /// Results of the `when` commands of entries.
+    when_cache: HashMap<String, bool>,
+    /// The image shown above the top-level page and its height.
+    header: Option<(cairo::ImageSurface, f64)>,
+    /// Layouts shared by identical texts of the entries.
+    text_cache: TextCache,
+    /// The context the texts are laid out in.
+    context: pango::Context,
+    /// The `@2x` variant of the header image, used at scales of 2 and more.
+    header_2x: Option<cairo::ImageSurface>,
 }
 
 struct MenuPage {
+    /// The size of the page, updated whenever its columns change.
+    width: f64,
+    height: f64,
+    item_height: f64,
+    columns: Vec<MenuColumn>,
+    parent: Option<usize>,
+}
+
+struct MenuColumn {
+    num_col_width: f64,
     key_col_width: f64,
     val_col_width: f64,
-    item_height: f64,
     items: Vec<MenuItem>,
-    parent: Option<usize>,
 }
 
 struct MenuItem {
-    action: Action,
+    /// `None` for separator rows.
+    action: Option<Action>,
     key_comp: ComputedText,
     val_comp: ComputedText,
     key: Key,
+    desc: String,
+    /// The description before substituting commands, if it has `desc_cmd`.
+    desc_cmd: Option<String>,
+    /// Whether to expand placeholders in the command.
+    placeholders: bool,
+    /// Whether the item is shown, but can not be selected.
+    disabled: bool,
+    /// Height of the row, including spacing.
+    height: f64,
+    /// The position of the item on the page, with `show_numbers`.
+    number: Option<ComputedText>,
+}
+
+/// A page of the menu, as printed by `--dump-menu`.
+#[derive(Serialize)]
+pub struct PageDump {
+    id: usize,
+    parent: Option<usize>,
+    /// The keys leading to this page from the top-level page.
+    path: Vec<String>,
+    entries: Vec<EntryDump>,
+}
+
+#[derive(Serialize)]
+pub struct EntryDump {
+    key: String,
+    desc: String,
+    cmd: Option<Vec<String>>,
+    /// The id of the page opened by this entry.
+    submenu: Option<usize>,
+    separator: bool,
+    disabled: bool,
 }
 
 #[derive(Clone)]
 pub enum Action {
     Quit,
-    Exec { cmd: String, keep_open: bool },
+    Exec {
+        cmd: Vec<String>,
+        then: config::Then,
+        capture: bool,
+        /// The message of the notification sent when the command is run.
+        notify: Option<String>,
+        /// Whether holding the key runs the command again.
+        repeat: bool,
+        /// How many times the command may run before the menu closes, from `keep_open`.
+        max_activations: Option<u32>,
+        /// Whether to wait for the command and update the descriptions of the page afterwards.
+        refresh: bool,
+        /// Environment variables describing the selected entry, set when it is selected.
+        env: Vec<(&'static str, String)>,
+    },
     Submenu(usize),
+    /// Copy the current page as text to the clipboard.
+    CopyPage,
+}
+
+impl Action {
+    /// Whether holding the key runs the command again, as long as the menu stays on the page.
+    /// Entries limited with `keep_open: { max: N }` do not repeat, since repeats are not counted.
+    pub fn repeats(&self) -> bool {
+        matches!(
+            self,
+            Self::Exec {
+                repeat: true,
+                capture: false,
+                max_activations: None,
+                ..
+            }
+        )
+    }
 }
 
 impl Menu {
@@ -43,10 +151,52 @@ impl Menu {
         let mut this = Self {
             pages: Vec::new(),
             cur_page: 0,
-            separator: ComputedText::new(&config.separator, &context, &config.font),
+            pending: Vec::new(),
+            case_insensitive: config.case_insensitive_keys,
+            cancel_keys: config.cancel_keys(),
+            parent_keys: config.parent_keys.clone(),
+            copy_key: config.copy_key.clone(),
+            column_padding: config.column_padding,
+            output: None,
+            desc_cmd_cache: HashMap::new(),
+            when_cache: HashMap::new(),
+            header: config
+                .header_image
+                .as_deref()
+                .map(crate::load_image)
+                .transpose()?
+                .map(|image| (image, config.header_height)),
+            text_cache: TextCache::default(),
+            context: context.clone(),
+            header_2x: config
+                .header_image
+                .as_deref()
+                .filter(|_| config.hidpi_images)
+                .and_then(|path| crate::load_image(&crate::hidpi_variant(path)).ok()),
+            separator: ComputedText::new(
+                &config.separator,
+                &context,
+                &config.font,
+                None,
+                false,
+                config.letter_spacing,
+            ),
         };
 
-        this.push_page(&context, &config.menu, config, None)?;
+        // At scale 2, the header is drawn twice as large as `header_height` says
+        if let (Some(image_2x), Some((width, height))) = (&this.header_2x, this.header_size(0)) {
+            let size = (image_2x.width(), image_2x.height());
+            let target = ((width * 2.0).round() as i32, (height * 2.0).round() as i32);
+            if size != target {
+                eprintln!(
+                    "warning: the @2x variant of header_image is {}x{}, but the header is {}x{} at scale 2",
+                    size.0, size.1, target.0, target.1,
+                );
+            }
+        }
+
+        this.push_page(&context, &config.menu, config, None, config.sort)?
+            .context("All entries of the menu are hidden by their `when` commands")?;
 
         Ok(this)
     }
@@ -57,7 +207,8 @@ impl Menu {
         entries: &config::Entries,
         config: &Config,
         parent: Option<usize>,
-    ) -> Result<usize> {
+        sort: config::Sort,
+    ) -> Result<Option<usize>> {
         if entries.0.is_empty() {
             bail!("Empty menu pages are not allowed");
         }
@@ -65,70 +216,437 @@ impl Menu {
         let cur_page = self.pages.len();
 
         self.pages.push(MenuPage {
-            key_col_width: 0.0,
-            val_col_width: 0.0,
-            item_height: self.separator.height,
-            items: Vec::new(),
+            width: 0.0,
+            height: 0.0,
+            item_height: 0.0,
+            columns: Vec::new(),
             parent,
         });
 
+        let mut items: Vec<MenuItem> = Vec::new();
         for (key, entry) in &entries.0 {
+            if let Some(cmd) = entry.when() {
+                if !self.condition_holds(cmd) {
+                    continue;
+                }
+            }
+
+            let key = &if self.case_insensitive {
+                key.to_lowercase()
+            } else {
+                key.clone()
+            };
             let item = match entry {
                 config::Entry::Cmd {
                     cmd,
                     desc,
+                    desc_cmd,
                     keep_open,
-                } => MenuItem {
-                    action: Action::Exec {
-                        cmd: cmd.into(),
-                        keep_open: *keep_open,
-                    },
-                    key_comp: ComputedText::new(&key.repr, context, &config.font),
-                    val_comp: ComputedText::new(desc, context, &config.font),
-                    key: key.clone(),
-                },
+                    then,
+                    placeholders,
+                    capture,
+                    when: _,
+                    disabled,
+                    notify,
+                    key_label: label,
+                    repeat,
+                    refresh,
+                } => {
+                    let template = desc_cmd.then(|| desc.clone());
+                    let desc = &self.desc_text(desc, *desc_cmd);
+                    let notify = match notify {
+                        None | Some(config::Notify::Enabled(false)) => None,
+                        Some(config::Notify::Enabled(true)) => Some(desc.clone()),
+                        Some(config::Notify::Message(message)) => Some(message.clone()),
+                    };
+                    MenuItem {
+                        action: Some(Action::Exec {
+                            cmd: cmd.clone(),
+                            then: then.unwrap_or(match keep_open {
+                                config::KeepOpen::Enabled(false) => config::Then::Quit,
+                                _ => config::Then::Stay,
+                            }),
+                            capture: *capture,
+                            notify,
+                            repeat: *repeat,
+                            max_activations: match (then, keep_open) {
+                                (None, config::KeepOpen::Max { max }) => Some(*max),
+                                _ => None,
+                            },
+                            refresh: *refresh,
+                            env: Vec::new(),
+                        }),
+                        key_comp: self.text_cache.get(
+                            &label.clone().unwrap_or_else(|| key_label(key, config)),
+                            context,
+                            &config.key_font(),
+                            None,
+                            false,
+                            config.letter_spacing,
+                        ),
+                        val_comp: self.text_cache.get(
+                            &desc_markup(desc, key, config),
+                            context,
+                            config.desc_font(),
+                            config.max_desc_width,
+                            config.wrap_desc,
+                            config.letter_spacing,
+                        ),
+                        key: key.clone(),
+                        desc: desc.clone(),
+                        desc_cmd: template,
+                        // The catch-all entry is not useful without knowing the typed key
+                        placeholders: *placeholders || key.is_wildcard(),
+                        disabled: *disabled,
+                        height: 0.0,
+                        number: None,
+                    }
+                }
                 config::Entry::Recursive {
                     submenu: entries,
                     desc,
+                    desc_cmd,
+                    when: _,
+                    disabled,
+                    key_label: label,
+                    sort: submenu_sort,
                 } => {
-                    let new_page = self.push_page(context, entries, config, Some(cur_page))?;
+                    let template = desc_cmd.then(|| desc.clone());
+                    let desc = &self.desc_text(desc, *desc_cmd);
+                    let submenu_sort = submenu_sort.unwrap_or(sort);
+                    // Submenus whose entries are all hidden are hidden too
+                    let Some(new_page) =
+                        self.push_page(context, entries, config, Some(cur_page), submenu_sort)?
+                    else {
+                        continue;
+                    };
                     MenuItem {
-                        action: Action::Submenu(new_page),
-                        key_comp: ComputedText::new(&key.repr, context, &config.font),
-                        val_comp: ComputedText::new(&format!("+{desc}"), context, &config.font),
+                        action: Some(Action::Submenu(new_page)),
+                        key_comp: self.text_cache.get(
+                            &label.clone().unwrap_or_else(|| key_label(key, config)),
+                            context,
+                            &config.key_font(),
+                            None,
+                            false,
+                            config.letter_spacing,
+                        ),
+                        val_comp: self.text_cache.get(
+                            &format!("+{}", desc_markup(desc, key, config)),
+                            context,
+                            config.desc_font(),
+                            config.max_desc_width,
+                            config.wrap_desc,
+                            config.letter_spacing,
+                        ),
                         key: key.clone(),
+                        desc: desc.clone(),
+                        desc_cmd: template,
+                        placeholders: false,
+                        disabled: *disabled,
+                        height: 0.0,
+                        number: None,
                     }
                 }
+                config::Entry::Separator {
+                    separator: label,
+                    when: _,
+                } => MenuItem {
+                    action: None,
+                    key_comp: self.text_cache.get(
+                        "",
+                        context,
+                        &config.font,
+                        None,
+                        false,
+                        config.letter_spacing,
+                    ),
+                    val_comp: self.text_cache.get(
+                        label.as_deref().unwrap_or_default(),
+                        context,
+                        &config.font,
+                        None,
+                        false,
+                        config.letter_spacing,
+                    ),
+                    key: key.clone(),
+                    desc: label.clone().unwrap_or_default(),
+                    desc_cmd: None,
+                    placeholders: false,
+                    disabled: false,
+                    height: 0.0,
+                    number: None,
+                },
             };
+            items.push(item);
+        }
 
-            if item.key_comp.height > self.pages[cur_page].item_height {
-                self.pages[cur_page].item_height = item.key_comp.height;
+        if items.is_empty() {
+            // Only hidden entries, so no pages were added after this one
+            self.pages.pop();
+            return Ok(None);
+        }
+
+        // Sort each section between separators on its own
+        for section in items.split_mut(|item| item.action.is_none()) {
+            match sort {
+                config::Sort::None => (),
+                config::Sort::Key => section.sort_by(|a, b| a.key.repr.cmp(&b.key.repr)),
+                config::Sort::Desc => {
+                    section.sort_by_cached_key(|item| item.desc.to_lowercase());
+                }
+            }
+        }
+
+        if config.show_numbers {
+            let selectable = items.iter_mut().filter(|item| item.action.is_some());
+            for (i, item) in selectable.enumerate() {
+                item.number = Some(self.text_cache.get(
+                    &format!("{}. ", i + 1),
+                    context,
+                    &config.font,
+                    None,
+                    false,
+                    config.letter_spacing,
+                ));
             }
-            if item.key_comp.width > self.pages[cur_page].key_col_width {
-                self.pages[cur_page].key_col_width = item.key_comp.width;
+        }
+
+        let rows_per_column = match config.layout {
+            config::Layout::SingleColumn => usize::MAX,
+            config::Layout::Grid => config.rows_per_column.unwrap_or(usize::MAX).max(1),
+        };
+        self.pages[cur_page].columns = split_columns(items, rows_per_column);
+        self.update_row_heights(cur_page, config);
+        self.update_page_size(cur_page);
+
+        Ok(Some(cur_page))
+    }
+
+    /// Compute the heights of the rows of `page` from the sizes of their texts.
+    fn update_row_heights(&mut self, page: usize, config: &Config) {
+        let row_height =
+            |content_height: f64| content_height * config.line_height + config.row_spacing;
+        let content_height = |item: &MenuItem| {
+            self.separator
+                .height
+                .max(item.key_comp.height)
+                .max(item.val_comp.height)
+        };
+        let page = &mut self.pages[page];
+        page.item_height = row_height(
+            page.items()
+                .map(content_height)
+                .fold(self.separator.height, f64::max),
+        );
+        for column in &mut page.columns {
+            for item in &mut column.items {
+                item.height = match config.uniform_rows {
+                    true => page.item_height,
+                    false => row_height(content_height(item)),
+                };
             }
+        }
+    }
 
-            if item.val_comp.height > self.pages[cur_page].item_height {
-                self.pages[cur_page].item_height = item.val_comp.height;
+    /// Redistribute the entries of every page into as many columns as fit into `max_width`,
+    /// together with the header image. Returns whether the columns of the current page changed.
+    pub fn relayout(&mut self, max_width: f64) -> bool {
+        let header_width = self.header_size(0).map_or(0.0, |(width, _)| width);
+        let mut changed = false;
+        for (i, page) in self.pages.iter_mut().enumerate() {
+            let old_rows = page.columns.first().map_or(0, |column| column.items.len());
+            let items: Vec<MenuItem> = page
+                .columns
+                .drain(..)
+                .flat_map(|column| column.items)
+                .collect();
+            let header_width = if i == 0 { header_width } else { 0.0 };
+            let fits = |rows| {
+                columns_width(&items, rows, &self.separator, self.column_padding).max(header_width)
+                    <= max_width
+            };
+            // Columns get narrower as rows are added, so look for the fewest rows which fit.
+            // If nothing fits, a single column is the narrowest layout.
+            let (mut min_rows, mut max_rows) = (1.min(items.len()), items.len());
+            while min_rows < max_rows {
+                let rows = (min_rows + max_rows) / 2;
+                if fits(rows) {
+                    max_rows = rows;
+                } else {
+                    min_rows = rows + 1;
+                }
             }
-            if item.val_comp.width > self.pages[cur_page].val_col_width {
-                self.pages[cur_page].val_col_width = item.val_comp.width;
+            let rows = min_rows;
+            if i == self.cur_page && rows != old_rows {
+                changed = true;
             }
+            page.columns = split_columns(items, rows);
+        }
+        for page in 0..self.pages.len() {
+            self.update_page_size(page);
+        }
+        changed
+    }
 
-            self.pages[cur_page].items.push(item);
+    /// Run the commands in the descriptions of the current page again and update them.
+    pub fn refresh_page(&mut self, config: &Config) {
+        self.desc_cmd_cache.clear();
+        let mut columns = mem::take(&mut self.pages[self.cur_page].columns);
+        for column in &mut columns {
+            for item in &mut column.items {
+                let Some(template) = &item.desc_cmd else {
+                    continue;
+                };
+                item.desc = self.desc_text(template, true);
+                let mut markup = desc_markup(&item.desc, &item.key, config);
+                if let Some(Action::Submenu(_)) = item.action {
+                    markup.insert(0, '+');
+                }
+                item.val_comp = self.text_cache.get(
+                    &markup,
+                    &self.context,
+                    config.desc_font(),
+                    config.max_desc_width,
+                    config.wrap_desc,
+                    config.letter_spacing,
+                );
+            }
+            column.val_col_width = column
+                .items
+                .iter()
+                .map(|i| i.val_comp.width)
+                .fold(0.0, f64::max);
         }
+        self.pages[self.cur_page].columns = columns;
+        self.update_row_heights(self.cur_page, config);
+        self.update_page_size(self.cur_page);
+    }
 
-        Ok(cur_page)
+    /// Whether the `when` command `cmd` of an entry succeeds.
+    fn condition_holds(&mut self, cmd: &str) -> bool {
+        *self.when_cache.entry(cmd.to_owned()).or_insert_with(|| {
+            Command::new("sh")
+                .args(["-c", cmd])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        })
+    }
+
+    /// The description of an entry, with `$(command)` replaced by the output of the command if
+    /// `desc_cmd` is set. Substitutions whose command fails are left as is.
+    fn desc_text(&mut self, desc: &str, desc_cmd: bool) -> String {
+        if !desc_cmd {
+            return desc.to_owned();
+        }
+
+        let mut text = String::with_capacity(desc.len());
+        let mut rest = desc;
+        while let Some(start) = rest.find("$(") {
+            text.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            // Find the matching parenthesis, allowing nested ones
+            let mut depth = 0;
+            let end = rest.char_indices().skip(1).find_map(|(i, c)| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => (),
+                }
+                (depth == 0).then_some(i)
+            });
+            let Some(end) = end else {
+                break;
+            };
+
+            let cmd = &rest[2..end];
+            let output = self
+                .desc_cmd_cache
+                .entry(cmd.to_owned())
+                .or_insert_with(|| {
+                    let output = Command::new("sh")
+                        .args(["-c", cmd])
+                        .stdin(Stdio::null())
+                        .output()
+                        .ok()
+                        .filter(|output| output.status.success())?;
+                    let output = String::from_utf8_lossy(&output.stdout);
+                    Some(pango::glib::markup_escape_text(output.trim_end()).into())
+                });
+            text.push_str(output.as_deref().unwrap_or(&rest[..=end]));
+            rest = &rest[end + 1..];
+        }
+        text.push_str(rest);
+        text
     }
 
     pub fn width(&self) -> f64 {
-        let page = &self.pages[self.cur_page];
-        page.key_col_width + page.val_col_width + self.separator.width
+        match &self.output {
+            Some(output) => output.width,
+            None => self.page_width(self.cur_page),
+        }
+    }
+
+    /// The width of `page`, ignoring captured output.
+    pub fn page_width(&self, page: usize) -> f64 {
+        self.pages[page].width
     }
 
     pub fn height(&self) -> f64 {
-        let page = &self.pages[self.cur_page];
-        page.item_height * page.items.len() as f64
+        match &self.output {
+            Some(output) => output.height,
+            None => self.page_height(self.cur_page),
+        }
+    }
+
+    /// The height of `page`, ignoring captured output.
+    pub fn page_height(&self, page: usize) -> f64 {
+        self.pages[page].height
+    }
+
+    /// The largest width and the largest height of all pages.
+    pub fn largest_size(&self) -> (f64, f64) {
+        self.pages.iter().fold((0.0, 0.0), |(width, height), page| {
+            (page.width.max(width), page.height.max(height))
+        })
+    }
+
+    /// Compute the size of `page` from its columns.
+    fn update_page_size(&mut self, page: usize) {
+        let header = self.header_size(page);
+        let columns_height = self.columns_height(page);
+        let page = &mut self.pages[page];
+        let columns_width: f64 = page
+            .columns
+            .iter()
+            .map(|column| column.width(&self.separator))
+            .sum();
+        let gaps = page.columns.len().saturating_sub(1);
+        let width = columns_width + self.column_padding * gaps as f64;
+        (page.width, page.height) = match header {
+            Some((header_width, header_height)) => (
+                width.max(header_width),
+                header_height + HEADER_GAP + columns_height,
+            ),
+            None => (width, columns_height),
+        };
+    }
+
+    fn columns_height(&self, page: usize) -> f64 {
+        self.pages[page]
+            .columns
+            .iter()
+            .map(MenuColumn::height)
+            .fold(0.0, f64::max)
+    }
+
+    /// The size of the header image, scaled to `header_height`, if it is shown on this page.
+    fn header_size(&self, page: usize) -> Option<(f64, f64)> {
+        let (image, height) = self.header.as_ref().filter(|_| page == 0)?;
+        let scale = height / image.height() as f64;
+        Some((image.width() as f64 * scale, *height))
     }
 
     pub fn render(
@@ -138,75 +656,1077 @@ impl Menu {
         dx: f64,
         dy: f64,
     ) -> Result<()> {
-        let page = &self.pages[self.cur_page];
-        let fg_color = config.color;
+        if let Some(output) = &self.output {
+            return output.render(
+                cairo_ctx,
+                text::RenderOptions {
+                    x: dx,
+                    y: dy,
+                    fg_color: config.color,
+                    height: output.height,
+                },
+            );
+        }
+        self.render_page(config, cairo_ctx, self.cur_page, dx, dy)
+    }
 
-        for (i, comp) in page.items.iter().enumerate() {
+    /// Render `page`, regardless of the current page and captured output.
+    pub fn render_page(
+        &self,
+        config: &config::Config,
+        cairo_ctx: &cairo::Context,
+        page: usize,
+        mut dx: f64,
+        mut dy: f64,
+    ) -> Result<()> {
+        if let (Some((image, _)), Some((width, height))) = (&self.header, self.header_size(page)) {
+            // The surface scale is applied to the context, so text is rendered at the output's
+            // resolution. Images need a variant with more pixels to look as crisp.
+            let (_, device_scale) = cairo_ctx.user_to_device_distance(0.0, 1.0)?;
+            let image = match &self.header_2x {
+                Some(image_2x) if device_scale >= 2.0 => image_2x,
+                _ => image,
+            };
+            let x = dx + (self.page_width(page) - width) * 0.5;
+            let scale = height / image.height() as f64;
+            cairo_ctx.save()?;
+            cairo_ctx.translate(x, dy);
+            cairo_ctx.scale(scale, scale);
+            cairo_ctx.set_source_surface(image, 0.0, 0.0)?;
+            cairo_ctx.paint()?;
+            cairo_ctx.restore()?;
+            dy += height + HEADER_GAP;
+        }
+
+        let columns_height = self.columns_height(page);
+        let page = &self.pages[page];
+
+        for (i, column) in page.columns.iter().enumerate() {
+            if i != 0 {
+                if let Some(separator) = &config.column_separator {
+                    let x = dx - self.column_padding * 0.5;
+                    cairo_ctx.save()?;
+                    separator.color.apply(cairo_ctx);
+                    cairo_ctx.set_line_width(separator.width);
+                    cairo_ctx.move_to(x, dy);
+                    cairo_ctx.line_to(x, dy + columns_height);
+                    cairo_ctx.stroke()?;
+                    cairo_ctx.restore()?;
+                }
+            }
+            self.render_column(config, cairo_ctx, column, dx, dy)?;
+            dx += column.width(&self.separator) + self.column_padding;
+        }
+
+        Ok(())
+    }
+
+    fn render_column(
+        &self,
+        config: &config::Config,
+        cairo_ctx: &cairo::Context,
+        column: &MenuColumn,
+        dx: f64,
+        dy: f64,
+    ) -> Result<()> {
+        let mut y = dy;
+        for comp in &column.items {
+            if comp.action.is_none() {
+                self.render_separator_row(config, cairo_ctx, column, comp, dx, y)?;
+                y += comp.height;
+                continue;
+            }
+            let fg_color = if comp.disabled {
+                config.disabled_color
+            } else {
+                config.color
+            };
+            if let Some(number) = &comp.number {
+                number.render(
+                    cairo_ctx,
+                    text::RenderOptions {
+                        x: dx + column.num_col_width - number.width,
+                        y,
+                        fg_color,
+                        height: comp.height,
+                    },
+                )?;
+            }
+            let dx = dx + column.num_col_width;
             comp.key_comp.render(
                 cairo_ctx,
                 text::RenderOptions {
-                    x: dx + page.key_col_width - comp.key_comp.width,
-                    y: dy + page.item_height * (i as f64),
+                    x: dx
+                        + config
+                            .key_align
+                            .offset(column.key_col_width, comp.key_comp.width),
+                    y,
                     fg_color,
-                    height: page.item_height,
+                    height: comp.height,
                 },
             )?;
             self.separator.render(
                 cairo_ctx,
                 text::RenderOptions {
-                    x: dx + page.key_col_width,
-                    y: dy + page.item_height * (i as f64),
+                    x: dx + column.key_col_width,
+                    y,
                     fg_color,
-                    height: page.item_height,
+                    height: comp.height,
                 },
             )?;
             comp.val_comp.render(
                 cairo_ctx,
                 text::RenderOptions {
-                    x: dx + page.key_col_width + self.separator.width,
-                    y: dy + page.item_height * (i as f64),
+                    x: dx
+                        + column.key_col_width
+                        + self.separator.width
+                        + config
+                            .desc_align
+                            .offset(column.val_col_width, comp.val_comp.width),
+                    y,
                     fg_color,
-                    height: page.item_height,
+                    height: comp.height,
                 },
             )?;
+            y += comp.height;
         }
 
         Ok(())
     }
 
-    pub fn get_action(&self, xkb: &xkb::State, sym: xkb::Keysym) -> Option<Action> {
+    /// Render a separator row: the label centered across the column, or a horizontal line.
+    fn render_separator_row(
+        &self,
+        config: &config::Config,
+        cairo_ctx: &cairo::Context,
+        column: &MenuColumn,
+        item: &MenuItem,
+        dx: f64,
+        y: f64,
+    ) -> Result<()> {
+        let column_width = column.width(&self.separator);
+        if item.desc.is_empty() {
+            let line_y = y + item.height * 0.5;
+            cairo_ctx.save()?;
+            config.color.apply(cairo_ctx);
+            cairo_ctx.set_line_width(1.0);
+            cairo_ctx.move_to(dx, line_y);
+            cairo_ctx.line_to(dx + column_width, line_y);
+            cairo_ctx.stroke()?;
+            cairo_ctx.restore()?;
+            Ok(())
+        } else {
+            item.val_comp.render(
+                cairo_ctx,
+                text::RenderOptions {
+                    x: dx + (column_width - item.val_comp.width) * 0.5,
+                    y,
+                    fg_color: config.color,
+                    height: item.height,
+                },
+            )
+        }
+    }
+
+    /// Whether `sym` completes or continues a key binding on the current page, including the
+    /// catch-all entry.
+    pub fn is_bound(&self, xkb: &xkb::State, sym: xkb::Keysym) -> bool {
+        let page = &self.pages[self.cur_page];
+        let mut pressed = SingleKey::pressed(xkb, sym);
+        if self.case_insensitive {
+            pressed = pressed.to_lowercase();
+        }
+        let starts_with = |keys: &[SingleKey], prefix: &[SingleKey]| {
+            keys.starts_with(prefix) && keys.get(prefix.len()) == Some(&pressed)
+        };
+        page.items().any(|i| {
+            starts_with(&i.key.keys, &self.pending)
+                || starts_with(&i.key.keys, &[])
+                || (i.key.is_wildcard()
+                    && !pressed.mod_ctrl
+                    && !pressed.mod_alt
+                    && sym.key_char().is_some_and(|c| !c.is_control()))
+        })
+    }
+
+    /// The action of the entry bound to `key` on the current page, if any.
+    pub fn key_action(&self, key: &Key) -> Option<Action> {
+        let key = &if self.case_insensitive {
+            key.to_lowercase()
+        } else {
+            key.clone()
+        };
+        let item = self.pages[self.cur_page]
+            .items()
+            .find(|item| item.key == *key)?;
+        let sym = key.keys.last()?.keysym;
+        item.get_action(&key.repr, sym, &self.page_path().join(" "))
+    }
+
+    pub fn get_action(&mut self, xkb: &xkb::State, sym: xkb::Keysym) -> Option<Action> {
         let page = &self.pages[self.cur_page];
 
         let mod_alt = xkb.mod_name_is_active(xkb::MOD_NAME_ALT, xkb::STATE_MODS_EFFECTIVE);
         let mod_ctrl = xkb.mod_name_is_active(xkb::MOD_NAME_CTRL, xkb::STATE_MODS_EFFECTIVE);
 
-        let item_i = page.items.iter().position(|i| {
-            i.key.mod_ctrl == mod_ctrl && i.key.mod_alt == mod_alt && i.key.keysym == sym
-        });
+        // Pressing a modifier does not interrupt a key sequence
+        if sym.is_modifier_key() && !self.pending.is_empty() {
+            return None;
+        }
 
-        if let Some(item_i) = item_i {
-            return Some(page.items[item_i].action.clone());
+        // Any key dismisses the captured output
+        if self.output.is_some() {
+            return (!sym.is_modifier_key()).then_some(Action::Submenu(self.cur_page));
         }
 
-        match sym {
-            xkb::Keysym::Escape => {
-                return Some(Action::Quit);
+        let path = self.page_path().join(" ");
+        let pressed = SingleKey::pressed(xkb, sym);
+        self.pending.push(if self.case_insensitive {
+            pressed.to_lowercase()
+        } else {
+            pressed.clone()
+        });
+        loop {
+            if let Some(item) = page.items().find(|i| i.key.keys == self.pending) {
+                self.pending.clear();
+                return item.get_action(&item.key.repr, sym, &path);
             }
-            xkb::Keysym::bracketleft | xkb::Keysym::g if mod_ctrl => {
-                return Some(Action::Quit);
+            if page.items().any(|i| i.key.keys.starts_with(&self.pending)) {
+                return None;
             }
-            xkb::Keysym::BackSpace => {
-                if let Some(parent) = page.parent {
-                    return Some(Action::Submenu(parent));
-                }
+            // Not a prefix of any sequence, try again with only the last key
+            if self.pending.len() == 1 {
+                self.pending.clear();
+                break;
             }
-            _ => (),
+            self.pending.drain(..self.pending.len() - 1);
+        }
+
+        if let Some(wildcard) = page.items().find(|i| i.key.is_wildcard()) {
+            let typed = sym.key_char().filter(|c| !c.is_control());
+            if let Some(typed) = typed.filter(|_| !mod_ctrl && !mod_alt) {
+                return wildcard.get_action(&typed.to_string(), sym, &path);
+            }
+        }
+
+        if self.cancel_keys.contains(&pressed) {
+            return Some(Action::Quit);
+        }
+
+        if self.parent_keys.contains(&pressed) {
+            if let Some(parent) = page.parent {
+                return Some(Action::Submenu(parent));
+            }
+        }
+
+        if self.copy_key.as_ref() == Some(&pressed) {
+            return Some(Action::CopyPage);
         }
 
         None
     }
 
+    /// The entries of the current page as plain text, one per line with the descriptions
+    /// aligned.
+    pub fn page_text(&self) -> String {
+        let page = &self.pages[self.cur_page];
+        let key_width = page
+            .items()
+            .map(|item| item.key.repr.chars().count())
+            .max()
+            .unwrap_or(0);
+        let mut text = String::new();
+        for item in page.items() {
+            let desc = item.desc.replace('\n', " ");
+            let line = match item.action {
+                None if desc.is_empty() => "---".to_owned(),
+                None => format!("--- {desc} ---"),
+                Some(Action::Submenu(_)) => format!("{:key_width$}  +{desc}", item.key.repr),
+                Some(_) => format!("{:key_width$}  {desc}", item.key.repr),
+            };
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
     pub fn set_page(&mut self, page: usize) {
         self.cur_page = page;
+        self.pending.clear();
+        self.output = None;
+    }
+
+    /// Whether the output of a command is shown instead of the current page.
+    pub fn has_output(&self) -> bool {
+        self.output.is_some()
+    }
+
+    /// Show the output of a command instead of the current page, keeping at most
+    /// `MAX_OUTPUT_LINES` lines of `MAX_OUTPUT_LINE_CHARS` characters.
+    pub fn show_output(&mut self, output: &str, config: &Config) {
+        let context = pango::Context::new();
+        let fontmap = pangocairo::FontMap::new();
+        context.set_font_map(Some(&fontmap));
+
+        let text = pango::glib::markup_escape_text(&truncate_output(output));
+
+        self.output = Some(ComputedText::new(
+            &text,
+            &context,
+            &config.font,
+            config.max_desc_width,
+            config.wrap_desc,
+            config.letter_spacing,
+        ));
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn current_page(&self) -> usize {
+        self.cur_page
+    }
+
+    /// The parent of the current page, if any.
+    pub fn parent_page(&self) -> Option<usize> {
+        self.pages[self.cur_page].parent
+    }
+
+    /// Whether `page` is a direct submenu of `parent`.
+    pub fn is_submenu_of(&self, page: usize, parent: usize) -> bool {
+        self.pages[page].parent == Some(parent)
+    }
+
+    /// The keys of the submenu entries leading from the root page to the current page.
+    pub fn page_path(&self) -> Vec<String> {
+        self.path_to(self.cur_page)
+    }
+
+    /// The keys of the submenu entries leading from the root page to `page`.
+    fn path_to(&self, mut page: usize) -> Vec<String> {
+        let mut path = Vec::new();
+        while let Some(parent) = self.pages[page].parent {
+            let item = self.pages[parent]
+                .items()
+                .find(|i| matches!(i.action, Some(Action::Submenu(p)) if p == page))
+                .expect("a page is a submenu of its parent");
+            path.push(item.key.repr.clone());
+            page = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// A human-readable name of `page` for messages.
+    fn page_name(&self, page: usize) -> String {
+        let path = self.path_to(page);
+        if path.is_empty() {
+            "the top-level page".to_owned()
+        } else {
+            format!("page `{}`", path.join(" > "))
+        }
+    }
+
+    /// Describe the entries which can never be selected because another entry on the same page
+    /// uses the same key, or a prefix of their key sequence.
+    pub fn key_conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        for (page_i, page) in self.pages.iter().enumerate() {
+            let location = self.page_name(page_i);
+
+            let items: Vec<&MenuItem> = page.items().filter(|i| !i.key.keys.is_empty()).collect();
+            for (i, item) in items.iter().enumerate() {
+                for earlier in &items[..i] {
+                    // The first entry with the exact key wins, and a single key wins over a
+                    // sequence starting with it
+                    let (winner, shadowed) = if item.key.keys.len() < earlier.key.keys.len() {
+                        (item, earlier)
+                    } else {
+                        (earlier, item)
+                    };
+                    if shadowed.key.keys.starts_with(&winner.key.keys) {
+                        conflicts.push(format!(
+                            "in {location}, `{}` ({}) can not be selected because of `{}` ({})",
+                            shadowed.key.repr, shadowed.desc, winner.key.repr, winner.desc,
+                        ));
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Describe the entries which override the cancel or parent keys, if enabled by
+    /// `warn_shadowed_keys`.
+    pub fn shadowed_builtin_keys(&self, config: &Config) -> Vec<String> {
+        let mut shadowed = Vec::new();
+        if !config.warn_shadowed_keys {
+            return shadowed;
+        }
+        for (page_i, page) in self.pages.iter().enumerate() {
+            for item in page.items() {
+                let Some(first) = item.key.keys.first() else {
+                    continue;
+                };
+                let builtin = if self.cancel_keys.contains(first) {
+                    "closes the menu"
+                } else if page.parent.is_some() && self.parent_keys.contains(first) {
+                    "goes back to the parent page"
+                } else {
+                    continue;
+                };
+                let location = self.page_name(page_i);
+                shadowed.push(format!(
+                    "in {location}, `{}` ({}) overrides a key which {builtin}",
+                    item.key.repr, item.desc,
+                ));
+            }
+        }
+        shadowed
+    }
+
+    /// A description of every page, for `--dump-menu`.
+    pub fn dump(&self) -> Vec<PageDump> {
+        self.pages
+            .iter()
+            .enumerate()
+            .map(|(id, page)| PageDump {
+                id,
+                parent: page.parent,
+                path: self.path_to(id),
+                entries: page
+                    .items()
+                    .map(|item| EntryDump {
+                        key: item.key.repr.clone(),
+                        desc: item.desc.clone(),
+                        cmd: match &item.action {
+                            Some(Action::Exec { cmd, .. }) => Some(cmd.clone()),
+                            _ => None,
+                        },
+                        submenu: match item.action {
+                            Some(Action::Submenu(page)) => Some(page),
+                            _ => None,
+                        },
+                        separator: item.action.is_none(),
+                        disabled: item.disabled,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Open the page reached by following `path` from the root page, stopping at the first key
+    /// which is not bound to a submenu.
+    pub fn navigate_to(&mut self, path: &[String]) {
+        let mut page = 0;
+        for key in path {
+            let next = self.pages[page].items().find_map(|i| match i.action {
+                Some(Action::Submenu(p)) if i.key.repr == *key => Some(p),
+                _ => None,
+            });
+            match next {
+                Some(next) => page = next,
+                None => break,
+            }
+        }
+        self.set_page(page);
+    }
+}
+
+impl MenuPage {
+    fn items(&self) -> impl Iterator<Item = &MenuItem> {
+        self.columns.iter().flat_map(|column| &column.items)
+    }
+}
+
+impl MenuColumn {
+    fn width(&self, separator: &ComputedText) -> f64 {
+        self.num_col_width + self.key_col_width + separator.width + self.val_col_width
+    }
+
+    fn height(&self) -> f64 {
+        self.items.iter().map(|item| item.height).sum()
+    }
+}
+
+impl MenuItem {
+    fn number_width(&self) -> f64 {
+        self.number.as_ref().map_or(0.0, |number| number.width)
+    }
+
+    /// The action of this item, selected with `key` on the page at `path`.
+    fn get_action(&self, key: &str, sym: xkb::Keysym, path: &str) -> Option<Action> {
+        if self.disabled {
+            return None;
+        }
+        match self.action.as_ref()? {
+            Action::Exec {
+                cmd,
+                then,
+                capture,
+                notify,
+                repeat,
+                max_activations,
+                refresh,
+                env: _,
+            } => Some(Action::Exec {
+                cmd: match self.placeholders {
+                    true => cmd
+                        .iter()
+                        .map(|cmd| {
+                            expand_placeholders(cmd, key, &self.desc, &xkb::keysym_get_name(sym))
+                        })
+                        .collect(),
+                    false => cmd.clone(),
+                },
+                then: *then,
+                capture: *capture,
+                notify: notify.clone(),
+                repeat: *repeat,
+                max_activations: *max_activations,
+                refresh: *refresh,
+                env: vec![
+                    ("WLR_WHICH_KEY", key.to_owned()),
+                    ("WLR_WHICH_KEY_DESC", self.desc.clone()),
+                    ("WLR_WHICH_KEY_PATH", path.to_owned()),
+                ],
+            }),
+            action => Some(action.clone()),
+        }
+    }
+}
+
+/// The width of `items` split into columns of `rows` items.
+fn columns_width(
+    items: &[MenuItem],
+    rows: usize,
+    separator: &ComputedText,
+    column_padding: f64,
+) -> f64 {
+    let widths = items.chunks(rows).map(|items| {
+        items.iter().map(MenuItem::number_width).fold(0.0, f64::max)
+            + items.iter().map(|i| i.key_comp.width).fold(0.0, f64::max)
+            + separator.width
+            + items.iter().map(|i| i.val_comp.width).fold(0.0, f64::max)
+    });
+    let columns = items.len().div_ceil(rows);
+    widths.sum::<f64>() + column_padding * columns.saturating_sub(1) as f64
+}
+
+/// Split `items` into columns of at most `rows_per_column` items.
+fn split_columns(items: Vec<MenuItem>, rows_per_column: usize) -> Vec<MenuColumn> {
+    let mut columns = Vec::new();
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        let items: Vec<MenuItem> = items.by_ref().take(rows_per_column).collect();
+        columns.push(MenuColumn {
+            num_col_width: items.iter().map(MenuItem::number_width).fold(0.0, f64::max),
+            key_col_width: items.iter().map(|i| i.key_comp.width).fold(0.0, f64::max),
+            val_col_width: items.iter().map(|i| i.val_comp.width).fold(0.0, f64::max),
+            items,
+        });
+    }
+    columns
+}
+
+/// The markup shown in the description column for the description `desc` of `key`. With
+/// `emphasize_key_in_desc`, the first occurrence of the key's character is underlined, ignoring
+/// case and skipping markup tags and entities.
+fn desc_markup(desc: &str, key: &Key, config: &Config) -> String {
+    let mut chars = key.repr.chars();
+    let (Some(key_char), None) = (chars.next(), chars.next()) else {
+        return desc.to_owned();
+    };
+    if !config.emphasize_key_in_desc || key.is_wildcard() {
+        return desc.to_owned();
+    }
+
+    let mut in_tag = false;
+    let mut in_entity = false;
+    for (i, c) in desc.char_indices() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            '&' if !in_tag => in_entity = true,
+            ';' if in_entity => in_entity = false,
+            _ if !in_tag && !in_entity && c.to_lowercase().eq(key_char.to_lowercase()) => {
+                let end = i + c.len_utf8();
+                return format!("{}<u>{}</u>{}", &desc[..i], &desc[i..end], &desc[end..]);
+            }
+            _ => (),
+        }
+    }
+    desc.to_owned()
+}
+
+/// The markup shown in the key column for `key`.
+fn key_label(key: &Key, config: &Config) -> String {
+    if config.pretty_keys || config.modifier_style.is_some() {
+        pango::glib::markup_escape_text(&key.label(config.pretty_keys, config.modifier_style))
+            .into()
+    } else {
+        key.repr.clone()
+    }
+}
+
+/// Replace `{{key}}`, `{{desc}}` and `{{keysym}}` in `cmd` with the corresponding shell-quoted
+/// values. `{{{{` is replaced with a literal `{{`, unknown placeholders are left as is.
+fn expand_placeholders(cmd: &str, key: &str, desc: &str, keysym: &str) -> String {
+    let mut expanded = String::with_capacity(cmd.len());
+    let mut rest = cmd;
+
+    while let Some(start) = rest.find("{{") {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("{{{{") {
+            expanded.push_str("{{");
+            rest = after;
+            continue;
+        }
+
+        let Some(end) = rest.find("}}") else {
+            break;
+        };
+        let value = match &rest[2..end] {
+            "key" => Some(key),
+            "desc" => Some(desc),
+            "keysym" => Some(keysym),
+            _ => None,
+        };
+        match value {
+            Some(value) => expanded.push_str(&shell_quote(value)),
+            None => expanded.push_str(&rest[..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Quote `s` so that it is passed as a single word to `sh`.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Limit the number of lines of command output and their length, marking the cut parts with `…`.
+fn truncate_output(output: &str) -> String {
+    let mut lines: Vec<String> = output
+        .trim_end()
+        .lines()
+        .take(MAX_OUTPUT_LINES)
+        .map(
+            |line| match line.char_indices().nth(MAX_OUTPUT_LINE_CHARS) {
+                Some((end, _)) => format!("{}…", &line[..end]),
+                None => line.to_owned(),
+            },
+        )
+        .collect();
+    if output.trim_end().lines().nth(MAX_OUTPUT_LINES).is_some() {
+        lines.push("…".to_owned());
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn build(config: &str) -> (Config, Menu) {
+        let config: Config = config.parse().unwrap();
+        let menu = Menu::new(&config).unwrap();
+        (config, menu)
+    }
+
+    /// The texts of the entries of the current page, in column order.
+    fn texts(menu: &Menu) -> Vec<(&ComputedText, &ComputedText)> {
+        menu.pages[menu.cur_page]
+            .items()
+            .map(|item| (&item.key_comp, &item.val_comp))
+            .collect()
+    }
+
+    /// The height of a row whose tallest text is `height` tall.
+    fn row_height(config: &Config, menu: &Menu, height: f64) -> f64 {
+        menu.separator.height.max(height) * config.line_height + config.row_spacing
+    }
+
+    #[test]
+    fn single_page_size() {
+        let (config, menu) = build(
+            "row_spacing: 4\nline_height: 1.5\nmenu:\n  \
+             a: { desc: Apps, cmd: a }\n  b: { desc: Browser, cmd: b }",
+        );
+        let texts = texts(&menu);
+        let key_width = texts.iter().map(|(key, _)| key.width).fold(0.0, f64::max);
+        let desc_width = texts.iter().map(|(_, desc)| desc.width).fold(0.0, f64::max);
+        assert_eq!(texts[1].1.width, desc_width);
+        assert_eq!(menu.width(), key_width + menu.separator.width + desc_width);
+
+        let text_height = texts[0].0.height.max(texts[0].1.height);
+        assert_eq!(menu.height(), row_height(&config, &menu, text_height) * 2.0);
+    }
+
+    #[test]
+    fn columns_size() {
+        let (config, menu) = build(
+            "rows_per_column: 2\nmenu:\n  a: { desc: A, cmd: a }\n  b: { desc: B, cmd: b }\n  \
+             c: { desc: Calculator, cmd: c }\n  d: { desc: D, cmd: d }\n  e: { desc: E, cmd: e }",
+        );
+        let texts = texts(&menu);
+        let column_width = |texts: &[(&ComputedText, &ComputedText)]| {
+            texts.iter().map(|(key, _)| key.width).fold(0.0, f64::max)
+                + menu.separator.width
+                + texts.iter().map(|(_, desc)| desc.width).fold(0.0, f64::max)
+        };
+        assert_eq!(menu.pages[0].columns.len(), 3);
+        assert_eq!(
+            menu.width(),
+            column_width(&texts[..2])
+                + column_width(&texts[2..4])
+                + column_width(&texts[4..])
+                + config.column_padding * 2.0
+        );
+        assert_eq!(menu.height(), menu.pages[0].item_height * 2.0);
+    }
+
+    #[test]
+    fn separators_size() {
+        let (config, menu) = build(
+            "menu:\n  a: { desc: A, cmd: a }\n  --- media: { separator: Media controls }\n  \
+             b: { desc: B, cmd: b }\n  --- end: { separator: ~ }",
+        );
+        let texts = texts(&menu);
+        assert_eq!(texts.len(), 4);
+        // The label of the separator is the widest description
+        let key_width = texts[0].0.width.max(texts[2].0.width);
+        assert_eq!(
+            menu.width(),
+            key_width + menu.separator.width + texts[1].1.width
+        );
+        let text_height = texts[0].0.height.max(texts[0].1.height);
+        assert_eq!(menu.height(), row_height(&config, &menu, text_height) * 4.0);
+    }
+
+    #[test]
+    fn multi_line_rows_size() {
+        let entries = "menu:\n  a: { desc: \"Two\\nlines\", cmd: a }\n  b: { desc: B, cmd: b }";
+        let (config, menu) = build(&format!("uniform_rows: false\n{entries}"));
+        let texts = texts(&menu);
+        let (one_line, two_lines) = (texts[1].1.height, texts[0].1.height);
+        assert!(two_lines > one_line * 1.5);
+        assert_eq!(
+            menu.height(),
+            row_height(&config, &menu, two_lines) + row_height(&config, &menu, one_line)
+        );
+
+        // Every row is as tall as the tallest one
+        let (config, menu) = build(&format!("uniform_rows: true\n{entries}"));
+        assert_eq!(menu.height(), row_height(&config, &menu, two_lines) * 2.0);
+    }
+
+    #[test]
+    fn dump_as_json() {
+        let config: Config = "menu:\n  p:\n    desc: Power \"off\"\n    submenu:\n      \
+                              s: { desc: Sleep, cmd: systemctl suspend }"
+            .parse()
+            .unwrap();
+        let menu = Menu::new(&config).unwrap();
+        let json = serde_json::to_value(menu.dump()).unwrap();
+        assert_eq!(json[0]["entries"][0]["key"], "p");
+        assert_eq!(json[0]["entries"][0]["desc"], "Power \"off\"");
+        assert_eq!(json[0]["entries"][0]["submenu"], 1);
+        assert_eq!(json[1]["parent"], 0);
+        assert_eq!(json[1]["path"], serde_json::json!(["p"]));
+        assert_eq!(
+            json[1]["entries"][0]["cmd"],
+            serde_json::json!(["systemctl suspend"])
+        );
+    }
+
+    #[test]
+    fn repeats() {
+        let config: Config = "menu:
+  a: { desc: A, cmd: a, repeat: true, keep_open: true }
+  b: { desc: B, cmd: b, repeat: true }
+  c: { desc: C, cmd: c, keep_open: true }
+  d: { desc: D, cmd: d, repeat: true, keep_open: { max: 3 } }
+  e: { desc: E, cmd: e, repeat: true, then: root }
+  f: { desc: F, cmd: f, repeat: true, keep_open: true, capture: true }
+  s:
+    desc: Submenu
+    submenu:
+      p: { desc: P, cmd: p, repeat: true, then: parent }"
+            .parse()
+            .unwrap();
+        let mut menu = Menu::new(&config).unwrap();
+        // Whether the repeat timer is started when `key` is pressed
+        let repeats = |menu: &Menu, key: &str, sticky: bool| {
+            let action = menu.key_action(&key.parse().unwrap()).unwrap();
+            let Action::Exec { then, .. } = action else {
+                panic!("{key} does not run a command");
+            };
+            action.repeats() && then.stays(sticky, menu.parent_page().is_none())
+        };
+
+        assert!(repeats(&menu, "a", false));
+        // Closes the menu, unless kept open by the sticky modifier
+        assert!(!repeats(&menu, "b", false));
+        assert!(repeats(&menu, "b", true));
+        assert!(!repeats(&menu, "c", false));
+        assert!(!repeats(&menu, "d", false));
+        assert!(!repeats(&menu, "e", true));
+        assert!(!repeats(&menu, "f", false));
+
+        menu.set_page(1);
+        assert!(!repeats(&menu, "p", false));
+        assert!(!repeats(&menu, "p", true));
+    }
+
+    #[test]
+    fn relayout_fits_columns() {
+        let entries: String = ('a'..='l')
+            .map(|key| format!("  {key}: {{ desc: Entry {key}, cmd: {key} }}\n"))
+            .collect();
+        let config: Config = format!("menu:\n{entries}").parse().unwrap();
+        let mut menu = Menu::new(&config).unwrap();
+        let items: Vec<MenuItem> = menu.pages[0]
+            .columns
+            .drain(..)
+            .flat_map(|column| column.items)
+            .collect();
+        let widths: Vec<f64> = (1..=items.len())
+            .map(|rows| columns_width(&items, rows, &menu.separator, menu.column_padding))
+            .collect();
+        let width = |rows: usize| widths[rows - 1];
+        menu.pages[0].columns = split_columns(items, 1);
+
+        menu.relayout(f64::INFINITY);
+        assert_eq!(menu.pages[0].columns.len(), 12);
+        // Nothing fits, so a single column is used
+        menu.relayout(0.0);
+        assert_eq!(menu.pages[0].columns.len(), 1);
+
+        for max_width in [
+            width(12),
+            width(6),
+            width(4) + 1.0,
+            width(3),
+            width(2) - 1.0,
+        ] {
+            menu.relayout(max_width);
+            let rows = menu.pages[0].columns[0].items.len();
+            assert!(menu.pages[0].width <= max_width, "{max_width}");
+            // The fewest rows which fit are used
+            assert!(width(rows - 1) > max_width, "{max_width}");
+        }
+    }
+
+    #[test]
+    fn relayout_fits_header() {
+        let config: Config = "menu:\n  a: { desc: A, cmd: a }\n  b: { desc: B, cmd: b }\n  \
+                              s: { desc: S, submenu: { c: { desc: C, cmd: c }, d: { desc: D, cmd: d } } }"
+            .parse()
+            .unwrap();
+        let mut menu = Menu::new(&config).unwrap();
+        let header = cairo::ImageSurface::create(cairo::Format::ARgb32, 1000, 10).unwrap();
+        menu.header = Some((header, 10.0));
+
+        // The columns would fit, but not next to the header
+        menu.relayout(500.0);
+        assert_eq!(menu.pages[0].columns.len(), 1);
+        assert_eq!(menu.pages[1].columns.len(), 2);
+        menu.relayout(1000.0);
+        assert_eq!(menu.pages[0].columns.len(), 3);
+        assert_eq!(menu.pages[0].width, 1000.0);
+    }
+
+    #[test]
+    fn layout_debug_inside_border() {
+        // Draw the layout boxes of the texts too, only on the thread of this test
+        text::LAYOUT_DEBUG.set(Some(true));
+        let mut content = None;
+        for border_width in [0.0f64, 1.0, 3.0, 2.5] {
+            let config: Config = format!(
+                "border_width: {border_width}\npadding: 2\nmenu:\n  \
+                 a: {{ desc: Apps, cmd: a }}\n  b: {{ desc: Browser, cmd: b }}"
+            )
+            .parse()
+            .unwrap();
+            let menu = Menu::new(&config).unwrap();
+            // Sized as the window in `surface_size`
+            let inset = config.padding() + config.border_width;
+            let width = (menu.width() + inset * 2.0).ceil() as usize;
+            let height = (menu.height() + inset * 2.0).ceil() as usize;
+            let mut surface =
+                cairo::ImageSurface::create(cairo::Format::ARgb32, width as i32, height as i32)
+                    .unwrap();
+            let cairo_ctx = cairo::Context::new(&surface).unwrap();
+            menu.render(&config, &cairo_ctx, inset, inset).unwrap();
+            drop(cairo_ctx);
+            let stride = surface.stride() as usize;
+            let data = surface.data().unwrap();
+
+            // Nothing is drawn over the border
+            let border = border_width.ceil() as usize;
+            for y in 0..height {
+                for x in 0..width {
+                    let on_border =
+                        x < border || y < border || x >= width - border || y >= height - border;
+                    let alpha = data[y * stride + x * 4 + 3];
+                    assert!(!on_border || alpha == 0, "{border_width}: {x}x{y}");
+                }
+            }
+
+            // Whole border widths only move the content
+            if border_width.fract() == 0.0 {
+                let rows: Vec<&[u8]> = (border..height - border)
+                    .map(|y| &data[y * stride + border * 4..y * stride + (width - border) * 4])
+                    .collect();
+                let rows = rows.concat();
+                match &content {
+                    None => content = Some(rows),
+                    Some(content) => assert!(*content == rows, "{border_width}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn extreme_theme() {
+        for theme in [
+            "corner_r: 1000\nborder_width: 100",
+            "corner_r: [1000, 0, 1000, 0]\npadding: 0",
+            "corner_r: 0\nborder_width: 0\npadding: 0\nrow_spacing: 0",
+        ] {
+            let config: Config = format!("{theme}\nmenu:\n  a: {{ desc: A, cmd: a }}")
+                .parse()
+                .unwrap();
+            let menu = Menu::new(&config).unwrap();
+            assert!(menu.width() > 0.0 && menu.width().is_finite(), "{theme}");
+            assert!(menu.height() > 0.0 && menu.height().is_finite(), "{theme}");
+
+            // The corners of the window do not overlap
+            let inset = config.padding() + config.border_width;
+            let width = menu.width() + inset * 2.0;
+            let height = menu.height() + inset * 2.0;
+            let r = config.corner_r.fit(width, height);
+            assert!(r.top_left + r.top_right <= width, "{theme}");
+            assert!(r.top_left + r.bottom_left <= height, "{theme}");
+            // Neither do the corners of the background inside the border
+            let r = config.corner_r.grow(-config.border_width * 0.5);
+            assert!([r.top_left, r.top_right, r.bottom_right, r.bottom_left]
+                .iter()
+                .all(|r| *r >= 0.0));
+        }
+    }
+
+    #[test]
+    fn filtered_pages() {
+        let config: Config = "menu:
+  a: { desc: A, cmd: a }
+  s:
+    desc: Hidden
+    submenu:
+      b: { desc: B, cmd: b, when: 'false' }
+      c: { desc: C, cmd: c, when: 'false' }"
+            .parse()
+            .unwrap();
+        let mut menu = Menu::new(&config).unwrap();
+        // The submenu without entries is hidden
+        assert_eq!(menu.page_count(), 1);
+        assert!(menu.key_action(&"s".parse().unwrap()).is_none());
+
+        // A page left without columns has no size
+        menu.pages[0].columns.clear();
+        menu.update_page_size(0);
+        assert_eq!((menu.width(), menu.height()), (0.0, 0.0));
+        menu.relayout(100.0);
+        assert_eq!((menu.width(), menu.height()), (0.0, 0.0));
+
+        let config: Config = "menu:\n  a: { desc: A, cmd: a, when: 'false' }"
+            .parse()
+            .unwrap();
+        let e = Menu::new(&config).err().unwrap();
+        assert!(e.to_string().contains("hidden by their `when`"), "{e}");
+    }
+
+    #[test]
+    fn column_fonts() {
+        // The widths of the key and description columns
+        let widths = |fonts: &str| {
+            let config: Config = format!("{fonts}\nmenu:\n  a: {{ desc: Applications, cmd: a }}")
+                .parse()
+                .unwrap();
+            let menu = Menu::new(&config).unwrap();
+            let item = menu.pages[0].items().next().unwrap();
+            (item.key_comp.width, item.val_comp.width)
+        };
+
+        let (key, desc) = widths("font: monospace 10");
+        let (big_key, big_desc) = widths("font: monospace 20");
+        assert!(big_key > key && big_desc > desc);
+        let (big_key, same_desc) = widths("font: monospace 10\nkey_font: monospace 20");
+        assert!(big_key > key);
+        assert_eq!(same_desc, desc);
+        let (same_key, big_desc) = widths("font: monospace 10\ndesc_font: monospace 20");
+        assert_eq!(same_key, key);
+        assert!(big_desc > desc);
+    }
+
+    #[test]
+    fn refresh_page() {
+        let status = std::env::temp_dir().join(format!("wlr-which-key-{}", std::process::id()));
+        fs::write(&status, "on").unwrap();
+        let config: Config = format!(
+            "menu:\n  t: {{ desc: '$(cat {})', desc_cmd: true, cmd: toggle, refresh: true }}",
+            status.display()
+        )
+        .parse()
+        .unwrap();
+        let mut menu = Menu::new(&config).unwrap();
+        let (width, height) = (menu.width(), menu.height());
+        assert_eq!(menu.pages[0].items().next().unwrap().desc, "on");
+
+        // Longer and taller
+        fs::write(&status, "disabled\nfor now").unwrap();
+        menu.refresh_page(&config);
+        fs::remove_file(&status).unwrap();
+        let item = menu.pages[0].items().next().unwrap();
+        assert_eq!(item.desc, "disabled\nfor now");
+        assert!(menu.width() > width);
+        assert!(menu.height() > height);
+        assert_eq!(menu.height(), item.height);
+        assert_eq!(menu.largest_size(), (menu.width(), menu.height()));
+    }
+
+    #[test]
+    fn output_is_truncated() {
+        assert_eq!(truncate_output("a\nb\n\n"), "a\nb");
+
+        let long = "x".repeat(MAX_OUTPUT_LINE_CHARS + 1);
+        let truncated = truncate_output(&format!("{long}\nb"));
+        assert_eq!(
+            truncated,
+            format!("{}…\nb", "x".repeat(MAX_OUTPUT_LINE_CHARS))
+        );
+        // Characters, not bytes, are counted
+        let wide = "é".repeat(MAX_OUTPUT_LINE_CHARS);
+        assert_eq!(truncate_output(&wide), wide);
+
+        let many = "line\n".repeat(MAX_OUTPUT_LINES + 5);
+        let truncated = truncate_output(&many);
+        assert_eq!(truncated.lines().count(), MAX_OUTPUT_LINES + 1);
+        assert!(truncated.ends_with("line\n…"));
+        assert_eq!(
+            truncate_output(&"line\n".repeat(MAX_OUTPUT_LINES))
+                .lines()
+                .count(),
+            MAX_OUTPUT_LINES
+        );
     }
 }
@@ -1,5 +1,8 @@
+use std::collections::HashMap;
+
 use crate::color::Color;
 use anyhow::Result;
+use pango::prelude::*;
 use pango::FontDescription;
 use pangocairo::{cairo, pango};
 
@@ -19,10 +22,40 @@ pub struct ComputedText {
 }
 
 impl ComputedText {
-    pub fn new(text: &str, context: &pango::Context, font: &FontDescription) -> Self {
+    /// Lay out `text`, which may span multiple lines. Lines wider than `max_width` are wrapped if
+    /// `wrap` is set, and ellipsized at the end otherwise. `letter_spacing` is added between
+    /// letters, in points.
+    pub fn new(
+        text: &str,
+        context: &pango::Context,
+        font: &FontDescription,
+        max_width: Option<f64>,
+        wrap: bool,
+        letter_spacing: f64,
+    ) -> Self {
         let layout = pango::Layout::new(context);
         layout.set_font_description(Some(font));
-        layout.set_markup(text);
+        // Show text which is not valid markup (e.g. with a stray `&`) as is, instead of nothing
+        if pango::parse_markup(text, '\0').is_ok() {
+            layout.set_markup(text);
+        } else {
+            layout.set_text(text);
+        }
+        if letter_spacing != 0.0 {
+            let attrs = layout.attributes().unwrap_or_default();
+            attrs.insert(pango::AttrInt::new_letter_spacing(
+                (letter_spacing * pango::SCALE as f64) as i32,
+            ));
+            layout.set_attributes(Some(&attrs));
+        }
+        if let Some(max_width) = max_width {
+            layout.set_width((max_width * pango::SCALE as f64) as i32);
+            if wrap {
+                layout.set_wrap(pango::WrapMode::WordChar);
+            } else {
+                layout.set_ellipsize(pango::EllipsizeMode::End);
+            }
+        }
 
         let (width, height) = layout.pixel_size();
 
@@ -42,7 +75,7 @@ impl ComputedText {
         options.fg_color.apply(context);
         pangocairo::functions::show_layout(context, &self.layout);
 
-        if std::env::var("WLR_WHICH_KEY_LAYOUT_DEBUG").as_deref() == Ok("1") {
+        if layout_debug() {
             Color::from_rgba(255, 0, 0, 255).apply(context);
             context.rectangle(0.0, 0.0, self.width, self.height);
             context.set_line_width(1.0);
@@ -54,3 +87,82 @@ impl ComputedText {
         Ok(())
     }
 }
+
+#[cfg(test)]
+thread_local! {
+    /// Overrides `WLR_WHICH_KEY_LAYOUT_DEBUG` for the tests running on this thread.
+    pub static LAYOUT_DEBUG: std::cell::Cell<Option<bool>> = const { std::cell::Cell::new(None) };
+}
+
+/// Whether the layout boxes of texts are drawn, with `WLR_WHICH_KEY_LAYOUT_DEBUG=1`.
+fn layout_debug() -> bool {
+    #[cfg(test)]
+    if let Some(debug) = LAYOUT_DEBUG.get() {
+        return debug;
+    }
+    std::env::var("WLR_WHICH_KEY_LAYOUT_DEBUG").as_deref() == Ok("1")
+}
+
+/// Layouts of the texts computed so far. Big menus repeat many texts, like keys in submenus and
+/// entry numbers, and laying them out is the slowest part of building a menu.
+#[derive(Default)]
+pub struct TextCache {
+    texts: HashMap<(String, FontDescription, Option<u64>, bool, u64), ComputedText>,
+}
+
+impl TextCache {
+    /// Same as [`ComputedText::new`], reusing the layout of an identical text. The layouts are
+    /// shared, so they must not be modified.
+    pub fn get(
+        &mut self,
+        text: &str,
+        context: &pango::Context,
+        font: &FontDescription,
+        max_width: Option<f64>,
+        wrap: bool,
+        letter_spacing: f64,
+    ) -> ComputedText {
+        let key = (
+            text.to_owned(),
+            font.clone(),
+            max_width.map(f64::to_bits),
+            wrap,
+            letter_spacing.to_bits(),
+        );
+        self.texts
+            .entry(key)
+            .or_insert_with(|| {
+                ComputedText::new(text, context, font, max_width, wrap, letter_spacing)
+            })
+            .clone()
+    }
+}
+
+/// The font which is used for `font` after fontconfig substitutions, if any font can be loaded.
+pub fn resolve_font(font: &FontDescription) -> Option<FontDescription> {
+    let context = pango::Context::new();
+    context.set_font_map(Some(&pangocairo::FontMap::new()));
+    context.load_font(font).map(|font| font.describe())
+}
+
+/// Whether `resolved` belongs to one of the families requested in `font`. Generic families (like
+/// `monospace`) are always substituted, so they count as found.
+pub fn family_found(font: &FontDescription, resolved: &FontDescription) -> bool {
+    let Some(requested) = font.family() else {
+        return true;
+    };
+    let resolved = resolved.family().unwrap_or_default();
+    requested.split(',').map(str::trim).any(|family| {
+        family.eq_ignore_ascii_case(&resolved)
+            || [
+                "monospace",
+                "sans",
+                "sans-serif",
+                "serif",
+                "system-ui",
+                "emoji",
+            ]
+            .iter()
+            .any(|generic| family.eq_ignore_ascii_case(generic))
+    })
+}